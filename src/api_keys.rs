@@ -0,0 +1,45 @@
+use sha2::Digest;
+
+/// Distinguishes API keys from login tokens in the Authorization header.
+pub const PREFIX: &str = "rdk_";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Scope {
+    RedirectsRead,
+    RedirectsWrite,
+    Billing,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::RedirectsRead => "redirects:read",
+            Scope::RedirectsWrite => "redirects:write",
+            Scope::Billing => "billing",
+        }
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = ();
+    fn from_str(src: &str) -> Result<Scope, Self::Err> {
+        match src {
+            "redirects:read" => Ok(Scope::RedirectsRead),
+            "redirects:write" => Ok(Scope::RedirectsWrite),
+            "billing" => Ok(Scope::Billing),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn generate() -> String {
+    format!("{}{}", PREFIX, uuid::Uuid::new_v4().to_simple())
+}
+
+/// Keys are only stored hashed, so they can't be recovered from the database.
+pub fn hash(key: &str) -> String {
+    sha2::Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
@@ -0,0 +1,191 @@
+use std::net::IpAddr;
+
+/// Where the client's IP address comes from, for rate limiting logins and recording sessions.
+///
+/// Behind a reverse proxy every connection comes from the proxy, so the address has to be taken
+/// from a header instead. Only the entries added by the trusted proxies are believed, since
+/// anything before them was sent by the client.
+pub enum ClientIPSource {
+    /// The address of the connection itself.
+    Peer,
+    /// The `X-Forwarded-For` header, as appended to by this many proxies.
+    XForwardedFor { trusted_proxies: usize },
+    /// The `for` parameters of the RFC 7239 `Forwarded` header, as appended to by this many
+    /// proxies.
+    Forwarded { trusted_proxies: usize },
+}
+
+impl ClientIPSource {
+    /// Reads `TRUSTED_PROXIES`, the number of reverse proxies in front of the server, and
+    /// `PROXY_IP_HEADER`, either `x-forwarded-for` (the default) or `forwarded`. Without
+    /// `TRUSTED_PROXIES`, the connection's address is used.
+    pub fn from_env() -> Self {
+        let trusted_proxies: usize = std::env::var("TRUSTED_PROXIES")
+            .ok()
+            .map(|value| value.parse().expect("Failed to parse TRUSTED_PROXIES"))
+            .unwrap_or(0);
+
+        if trusted_proxies == 0 {
+            return ClientIPSource::Peer;
+        }
+
+        match std::env::var("PROXY_IP_HEADER")
+            .map(|value| value.to_lowercase())
+            .as_ref()
+            .map(|value| value.as_str())
+        {
+            Ok("x-forwarded-for") | Err(_) => ClientIPSource::XForwardedFor { trusted_proxies },
+            Ok("forwarded") => ClientIPSource::Forwarded { trusted_proxies },
+            Ok(other) => panic!("Unknown PROXY_IP_HEADER: {}", other),
+        }
+    }
+
+    /// Falls back to the connection's address if the header is missing or wasn't set by enough
+    /// proxies.
+    pub fn client_ip(&self, headers: &hyper::HeaderMap, peer: IpAddr) -> IpAddr {
+        let (entries, trusted_proxies) = match self {
+            ClientIPSource::Peer => return peer,
+            ClientIPSource::XForwardedFor { trusted_proxies } => (
+                header_list(headers, "x-forwarded-for")
+                    .into_iter()
+                    .map(|entry| entry.parse().ok())
+                    .collect::<Vec<Option<IpAddr>>>(),
+                *trusted_proxies,
+            ),
+            ClientIPSource::Forwarded { trusted_proxies } => (
+                header_list(headers, "forwarded")
+                    .into_iter()
+                    .map(|entry| forwarded_for(&entry))
+                    .collect(),
+                *trusted_proxies,
+            ),
+        };
+
+        if entries.len() < trusted_proxies {
+            return peer;
+        }
+
+        entries[entries.len() - trusted_proxies].unwrap_or(peer)
+    }
+}
+
+/// The comma-separated entries of every instance of a header, in order.
+fn header_list(headers: &hyper::HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|entry| entry.trim().to_owned())
+        .collect()
+}
+
+/// The address in the `for` parameter of a `Forwarded` element, like `for="[2001:db8::1]:4711"`.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    let value = element.split(';').find_map(|pair| {
+        let pair = pair.trim();
+        if pair.len() > 4 && pair[..4].eq_ignore_ascii_case("for=") {
+            Some(pair[4..].trim_matches('"'))
+        } else {
+            None
+        }
+    })?;
+
+    if value.starts_with('[') {
+        value[1..].split(']').next()?.parse().ok()
+    } else {
+        value.split(':').next()?.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> IpAddr {
+        "10.0.0.2".parse().unwrap()
+    }
+
+    fn headers(name: &'static str, values: &[&str]) -> hyper::HeaderMap {
+        let mut headers = hyper::HeaderMap::new();
+        for value in values {
+            headers.append(name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn peer_ignores_headers() {
+        let headers = headers("x-forwarded-for", &["192.0.2.1"]);
+
+        assert_eq!(ClientIPSource::Peer.client_ip(&headers, peer()), peer());
+    }
+
+    #[test]
+    fn x_forwarded_for_skips_client_supplied_entries() {
+        let source = ClientIPSource::XForwardedFor { trusted_proxies: 1 };
+        let headers = headers("x-forwarded-for", &["198.51.100.7, 192.0.2.1"]);
+
+        assert_eq!(
+            source.client_ip(&headers, peer()),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn x_forwarded_for_across_proxies_and_header_lines() {
+        let source = ClientIPSource::XForwardedFor { trusted_proxies: 2 };
+        let headers = headers("x-forwarded-for", &["198.51.100.7", "192.0.2.1, 10.0.0.1"]);
+
+        assert_eq!(
+            source.client_ip(&headers, peer()),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn x_forwarded_for_falls_back_to_peer() {
+        let source = ClientIPSource::XForwardedFor { trusted_proxies: 2 };
+
+        assert_eq!(
+            source.client_ip(&headers("x-forwarded-for", &["192.0.2.1"]), peer()),
+            peer()
+        );
+        assert_eq!(source.client_ip(&hyper::HeaderMap::new(), peer()), peer());
+        assert_eq!(
+            source.client_ip(&headers("x-forwarded-for", &["junk, 10.0.0.1"]), peer()),
+            peer()
+        );
+    }
+
+    #[test]
+    fn forwarded_for_parameters() {
+        let source = ClientIPSource::Forwarded { trusted_proxies: 1 };
+
+        assert_eq!(
+            source.client_ip(
+                &headers(
+                    "forwarded",
+                    &["for=198.51.100.7, for=192.0.2.60;proto=http;by=203.0.113.43"]
+                ),
+                peer()
+            ),
+            "192.0.2.60".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            source.client_ip(
+                &headers("forwarded", &["For=\"[2001:db8:cafe::17]:4711\""]),
+                peer()
+            ),
+            "2001:db8:cafe::17".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            source.client_ip(&headers("forwarded", &["for=\"192.0.2.60:4711\""]), peer()),
+            "192.0.2.60".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            source.client_ip(&headers("forwarded", &["for=unknown"]), peer()),
+            peer()
+        );
+    }
+}
@@ -0,0 +1,675 @@
+use futures::{Future, Stream};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{tack_on, DbPool, ErrorWrapper};
+
+const IP_FREE_ATTEMPTS: u32 = 20;
+const ACCOUNT_FREE_ATTEMPTS: u32 = 5;
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Failures older than this are forgotten.
+const RESET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+/// Attempts still in progress after this long are assumed to have been abandoned, like when the
+/// client disconnects before the password check finishes.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The in-memory store starts evicting counters beyond this many.
+const MEMORY_STORE_MAX_SIZE: usize = 10000;
+
+/// How often counters that no longer matter are removed from the store.
+const PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+pub struct AttemptInfo {
+    pub key: String,
+    pub failures: u32,
+    pub since_last_failure: Duration,
+    /// Attempts that got past the lockout check and haven't finished yet.
+    pub pending: u32,
+}
+
+pub trait AttemptStore: Send + Sync {
+    /// Starts an attempt against each of `keys`, unless any of them is locked out or already has
+    /// as many attempts in progress as it may. In that case nothing is recorded, and it resolves
+    /// to how long to wait before trying again.
+    ///
+    /// Every started attempt must be finished with `record_failure`, `release` or `clear`.
+    fn begin_attempt(
+        &self,
+        keys: &[String],
+    ) -> Box<dyn Future<Item = Option<Duration>, Error = crate::Error> + Send>;
+    /// Finishes an attempt as a failure, which is what the lockout is measured from.
+    fn record_failure(
+        &self,
+        keys: &[String],
+    ) -> Box<dyn Future<Item = (), Error = crate::Error> + Send>;
+    /// Finishes an attempt without counting it as a failure.
+    fn release(&self, keys: &[String]) -> Box<dyn Future<Item = (), Error = crate::Error> + Send>;
+    /// Finishes an attempt and forgets all previous failures.
+    fn clear(&self, keys: &[String]) -> Box<dyn Future<Item = (), Error = crate::Error> + Send>;
+    /// Removes counters whose failures have been forgotten and that have no attempts in progress.
+    fn purge(&self) -> Box<dyn Future<Item = (), Error = crate::Error> + Send>;
+}
+
+struct MemoryEntry {
+    failures: u32,
+    last_failure: Instant,
+    pending: u32,
+    last_attempt: Instant,
+}
+
+impl MemoryEntry {
+    fn new(now: Instant) -> Self {
+        Self {
+            failures: 0,
+            last_failure: now,
+            pending: 0,
+            last_attempt: now,
+        }
+    }
+
+    fn failures(&self) -> u32 {
+        if self.last_failure.elapsed() >= RESET_AFTER {
+            0
+        } else {
+            self.failures
+        }
+    }
+
+    fn pending(&self) -> u32 {
+        if self.last_attempt.elapsed() >= PENDING_TIMEOUT {
+            0
+        } else {
+            self.pending
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.failures() == 0 && self.pending() == 0
+    }
+
+    fn info(&self, key: &str) -> AttemptInfo {
+        AttemptInfo {
+            key: key.to_owned(),
+            failures: self.failures,
+            since_last_failure: self.last_failure.elapsed(),
+            pending: self.pending(),
+        }
+    }
+}
+
+/// Keeps counters in this process only.
+#[derive(Default)]
+pub struct MemoryAttemptStore {
+    attempts: Mutex<HashMap<String, MemoryEntry>>,
+}
+
+/// Makes room in a full in-memory store. Counters that have reached a lockout or have attempts in
+/// progress are never evicted, since that would lift the lockout, so the store may still end up
+/// beyond its limit.
+fn make_room(attempts: &mut HashMap<String, MemoryEntry>) {
+    attempts.retain(|_, entry| !entry.is_idle());
+    if attempts.len() < MEMORY_STORE_MAX_SIZE {
+        return;
+    }
+
+    // then the counters that are still within their free attempts, least recently failed first
+    let mut evictable: Vec<(Instant, String)> = attempts
+        .iter()
+        .filter(|(key, entry)| entry.pending() == 0 && entry.failures() < free_attempts(key))
+        .map(|(key, entry)| (entry.last_failure, key.clone()))
+        .collect();
+    evictable.sort_unstable();
+
+    let excess = attempts.len() - MEMORY_STORE_MAX_SIZE / 2;
+    for (_, key) in evictable.into_iter().take(excess) {
+        attempts.remove(&key);
+    }
+}
+
+impl AttemptStore for MemoryAttemptStore {
+    fn begin_attempt(
+        &self,
+        keys: &[String],
+    ) -> Box<dyn Future<Item = Option<Duration>, Error = crate::Error> + Send> {
+        let mut attempts = self.attempts.lock().unwrap();
+
+        let infos: Vec<AttemptInfo> = keys
+            .iter()
+            .filter_map(|key| attempts.get(key).map(|entry| entry.info(key)))
+            .collect();
+        if let Some(retry_after) = turned_away(&infos) {
+            return Box::new(futures::future::ok(Some(retry_after)));
+        }
+
+        if attempts.len() >= MEMORY_STORE_MAX_SIZE {
+            make_room(&mut attempts);
+        }
+
+        let now = Instant::now();
+        for key in keys {
+            let entry = attempts
+                .entry(key.clone())
+                .or_insert_with(|| MemoryEntry::new(now));
+            entry.pending = entry.pending() + 1;
+            entry.last_attempt = now;
+        }
+
+        Box::new(futures::future::ok(None))
+    }
+
+    fn record_failure(
+        &self,
+        keys: &[String],
+    ) -> Box<dyn Future<Item = (), Error = crate::Error> + Send> {
+        let mut attempts = self.attempts.lock().unwrap();
+
+        let now = Instant::now();
+        for key in keys {
+            let entry = attempts
+                .entry(key.clone())
+                .or_insert_with(|| MemoryEntry::new(now));
+            entry.failures = entry.failures() + 1;
+            entry.last_failure = now;
+            entry.pending = entry.pending().saturating_sub(1);
+        }
+
+        Box::new(futures::future::ok(()))
+    }
+
+    fn release(&self, keys: &[String]) -> Box<dyn Future<Item = (), Error = crate::Error> + Send> {
+        let mut attempts = self.attempts.lock().unwrap();
+        for key in keys {
+            if let Some(entry) = attempts.get_mut(key) {
+                entry.pending = entry.pending().saturating_sub(1);
+                if entry.is_idle() {
+                    attempts.remove(key);
+                }
+            }
+        }
+
+        Box::new(futures::future::ok(()))
+    }
+
+    fn clear(&self, keys: &[String]) -> Box<dyn Future<Item = (), Error = crate::Error> + Send> {
+        let mut attempts = self.attempts.lock().unwrap();
+        for key in keys {
+            attempts.remove(key);
+        }
+
+        Box::new(futures::future::ok(()))
+    }
+
+    fn purge(&self) -> Box<dyn Future<Item = (), Error = crate::Error> + Send> {
+        self.attempts
+            .lock()
+            .unwrap()
+            .retain(|_, entry| !entry.is_idle());
+
+        Box::new(futures::future::ok(()))
+    }
+}
+
+/// Keeps counters in the `login_attempts` table, so they are shared between instances.
+pub struct PostgresAttemptStore {
+    db_pool: DbPool,
+}
+
+impl PostgresAttemptStore {
+    pub fn new(db_pool: DbPool) -> Self {
+        Self { db_pool }
+    }
+}
+
+fn postgres_release(
+    db_pool: &DbPool,
+    keys: Vec<String>,
+) -> impl Future<Item = (), Error = crate::Error> + Send {
+    let reset_after = RESET_AFTER.as_secs() as i32;
+
+    db_pool
+        .run(move |mut conn| {
+            // counters left with nothing to remember are removed rather than kept at zero
+            conn.prepare("WITH removed AS (DELETE FROM login_attempts WHERE key = ANY($1) AND pending <= 1 AND (failures = 0 OR last_failure < localtimestamp - $2::INTEGER * INTERVAL '1 second') RETURNING key) UPDATE login_attempts SET pending=GREATEST(pending - 1, 0) WHERE key = ANY($1) AND key NOT IN (SELECT key FROM removed)")
+                .then(|res| tack_on(res, conn))
+                .and_then(move |(stmt, mut conn)| {
+                    conn.execute(&stmt, &[&keys, &reset_after])
+                        .map(|_| ())
+                        .then(|res| tack_on(res, conn))
+                })
+        })
+        .map_err(ErrorWrapper::from)
+        .map_err(crate::Error::internal)
+}
+
+impl AttemptStore for PostgresAttemptStore {
+    fn begin_attempt(
+        &self,
+        keys: &[String],
+    ) -> Box<dyn Future<Item = Option<Duration>, Error = crate::Error> + Send> {
+        let db_pool = self.db_pool.clone();
+        let keys = keys.to_vec();
+        let pending_timeout = PENDING_TIMEOUT.as_secs() as i32;
+
+        Box::new(
+            self.db_pool
+                .run({
+                    let keys = keys.clone();
+                    move |mut conn| {
+                        conn.prepare("SELECT key, failures, EXTRACT(EPOCH FROM localtimestamp - last_failure)::INTEGER, CASE WHEN last_attempt < localtimestamp - $2::INTEGER * INTERVAL '1 second' THEN 0 ELSE pending END FROM login_attempts WHERE key = ANY($1)")
+                            .then(|res| tack_on(res, conn))
+                            .and_then(move |(stmt, mut conn)| {
+                                conn.query(&stmt, &[&keys, &pending_timeout])
+                                    .map(|row| {
+                                        let failures: i32 = row.get(1);
+                                        let since_last_failure: i32 = row.get(2);
+                                        let pending: i32 = row.get(3);
+                                        AttemptInfo {
+                                            key: row.get(0),
+                                            failures: failures.max(0) as u32,
+                                            since_last_failure: Duration::from_secs(
+                                                since_last_failure.max(0) as u64,
+                                            ),
+                                            pending: pending.max(0) as u32,
+                                        }
+                                    })
+                                    .collect()
+                                    .then(|res| tack_on(res, conn))
+                            })
+                    }
+                })
+                .map_err(ErrorWrapper::from)
+                .map_err(crate::Error::internal)
+                .and_then(move |infos: Vec<AttemptInfo>| {
+                    if let Some(retry_after) = turned_away(&infos) {
+                        return futures::future::Either::A(futures::future::ok(Some(retry_after)));
+                    }
+
+                    let mut expected_failures = Vec::with_capacity(keys.len());
+                    let mut pending_limits = Vec::with_capacity(keys.len());
+                    for key in &keys {
+                        match infos.iter().find(|info| &info.key == key) {
+                            Some(info) => {
+                                expected_failures.push(info.failures as i32);
+                                pending_limits.push(max_pending(info) as i32);
+                            }
+                            None => {
+                                expected_failures.push(0);
+                                pending_limits.push(free_attempts(key) as i32);
+                            }
+                        }
+                    }
+
+                    futures::future::Either::B(
+                        db_pool
+                            .run({
+                                let keys = keys.clone();
+                                move |mut conn| {
+                                    // only goes ahead for counters nobody else changed since they were checked above
+                                    conn.prepare("WITH expected AS (SELECT * FROM UNNEST($1::TEXT[], $2::INTEGER[], $3::INTEGER[]) AS expected (key, failures, max_pending)), updated AS (UPDATE login_attempts SET pending=(CASE WHEN login_attempts.last_attempt < localtimestamp - $4::INTEGER * INTERVAL '1 second' THEN 0 ELSE login_attempts.pending END) + 1, last_attempt=localtimestamp FROM expected WHERE login_attempts.key=expected.key AND login_attempts.failures=expected.failures AND (CASE WHEN login_attempts.last_attempt < localtimestamp - $4::INTEGER * INTERVAL '1 second' THEN 0 ELSE login_attempts.pending END) < expected.max_pending RETURNING login_attempts.key), inserted AS (INSERT INTO login_attempts (key, failures, last_failure, pending, last_attempt) SELECT key, 0, localtimestamp, 1, localtimestamp FROM expected WHERE failures=0 AND NOT EXISTS (SELECT 1 FROM login_attempts WHERE login_attempts.key=expected.key) ON CONFLICT (key) DO NOTHING RETURNING key) SELECT key FROM updated UNION ALL SELECT key FROM inserted")
+                                        .then(|res| tack_on(res, conn))
+                                        .and_then(move |(stmt, mut conn)| {
+                                            conn.query(&stmt, &[&keys, &expected_failures, &pending_limits, &pending_timeout])
+                                                .map(|row| -> String { row.get(0) })
+                                                .collect()
+                                                .then(|res| tack_on(res, conn))
+                                        })
+                                }
+                            })
+                            .map_err(ErrorWrapper::from)
+                            .map_err(crate::Error::internal)
+                            .and_then({
+                                let db_pool = db_pool.clone();
+                                move |started: Vec<String>| {
+                                    if started.len() == keys.len() {
+                                        futures::future::Either::A(futures::future::ok(None))
+                                    } else {
+                                        // lost a race with a concurrent attempt, so back out and have the client retry
+                                        futures::future::Either::B(
+                                            postgres_release(&db_pool, started)
+                                                .map(|_| Some(Duration::from_secs(1))),
+                                        )
+                                    }
+                                }
+                            }),
+                    )
+                }),
+        )
+    }
+
+    fn record_failure(
+        &self,
+        keys: &[String],
+    ) -> Box<dyn Future<Item = (), Error = crate::Error> + Send> {
+        let keys = keys.to_vec();
+        let reset_after = RESET_AFTER.as_secs() as i32;
+
+        Box::new(
+            self.db_pool
+                .run(move |mut conn| {
+                    conn.prepare("INSERT INTO login_attempts (key, failures, last_failure, pending, last_attempt) SELECT UNNEST($1::TEXT[]), 1, localtimestamp, 0, localtimestamp ON CONFLICT (key) DO UPDATE SET failures=(CASE WHEN login_attempts.last_failure < localtimestamp - $2::INTEGER * INTERVAL '1 second' THEN 0 ELSE login_attempts.failures END) + 1, last_failure=localtimestamp, pending=GREATEST(login_attempts.pending - 1, 0)")
+                        .then(|res| tack_on(res, conn))
+                        .and_then(move |(stmt, mut conn)| {
+                            conn.execute(&stmt, &[&keys, &reset_after])
+                                .map(|_| ())
+                                .then(|res| tack_on(res, conn))
+                        })
+                })
+                .map_err(ErrorWrapper::from)
+                .map_err(crate::Error::internal),
+        )
+    }
+
+    fn release(&self, keys: &[String]) -> Box<dyn Future<Item = (), Error = crate::Error> + Send> {
+        Box::new(postgres_release(&self.db_pool, keys.to_vec()))
+    }
+
+    fn clear(&self, keys: &[String]) -> Box<dyn Future<Item = (), Error = crate::Error> + Send> {
+        let keys = keys.to_vec();
+
+        Box::new(
+            self.db_pool
+                .run(move |mut conn| {
+                    conn.prepare("DELETE FROM login_attempts WHERE key = ANY($1)")
+                        .then(|res| tack_on(res, conn))
+                        .and_then(move |(stmt, mut conn)| {
+                            conn.execute(&stmt, &[&keys])
+                                .map(|_| ())
+                                .then(|res| tack_on(res, conn))
+                        })
+                })
+                .map_err(ErrorWrapper::from)
+                .map_err(crate::Error::internal),
+        )
+    }
+
+    fn purge(&self) -> Box<dyn Future<Item = (), Error = crate::Error> + Send> {
+        let reset_after = RESET_AFTER.as_secs() as i32;
+        let pending_timeout = PENDING_TIMEOUT.as_secs() as i32;
+
+        Box::new(
+            self.db_pool
+                .run(move |mut conn| {
+                    conn.prepare("DELETE FROM login_attempts WHERE (failures = 0 OR last_failure < localtimestamp - $1::INTEGER * INTERVAL '1 second') AND (pending = 0 OR last_attempt < localtimestamp - $2::INTEGER * INTERVAL '1 second')")
+                        .then(|res| tack_on(res, conn))
+                        .and_then(move |(stmt, mut conn)| {
+                            conn.execute(&stmt, &[&reset_after, &pending_timeout])
+                                .map(|_| ())
+                                .then(|res| tack_on(res, conn))
+                        })
+                })
+                .map_err(ErrorWrapper::from)
+                .map_err(crate::Error::internal),
+        )
+    }
+}
+
+pub fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+pub fn account_key(email: &str) -> String {
+    format!("email:{}", email.to_lowercase())
+}
+
+fn free_attempts(key: &str) -> u32 {
+    if key.starts_with("ip:") {
+        IP_FREE_ATTEMPTS
+    } else {
+        ACCOUNT_FREE_ATTEMPTS
+    }
+}
+
+/// Failures that haven't been forgotten yet.
+fn current_failures(info: &AttemptInfo) -> u32 {
+    if info.since_last_failure >= RESET_AFTER {
+        0
+    } else {
+        info.failures
+    }
+}
+
+/// How much longer a key is locked out for, backing off exponentially once its free attempts
+/// are used up.
+fn lockout_remaining(info: &AttemptInfo) -> Option<Duration> {
+    let failures = current_failures(info);
+    let free_attempts = free_attempts(&info.key);
+    if failures < free_attempts {
+        return None;
+    }
+
+    let exponent = (failures - free_attempts).min(31);
+    let lockout = Duration::from_secs(1u64 << exponent).min(MAX_LOCKOUT);
+
+    if lockout > info.since_last_failure {
+        Some(lockout - info.since_last_failure)
+    } else {
+        None
+    }
+}
+
+/// How many attempts may be in progress at once for a key: as many as it has free attempts left,
+/// then one at a time, so a burst of concurrent requests can't get around the lockout.
+fn max_pending(info: &AttemptInfo) -> u32 {
+    let failures = current_failures(info);
+    let free_attempts = free_attempts(&info.key);
+    if failures < free_attempts {
+        free_attempts - failures
+    } else {
+        1
+    }
+}
+
+/// How long to wait before retrying, if an attempt against the keys of `infos` can't start yet.
+fn turned_away(infos: &[AttemptInfo]) -> Option<Duration> {
+    infos
+        .iter()
+        .filter_map(|info| {
+            lockout_remaining(info).or_else(|| {
+                if info.pending >= max_pending(info) {
+                    Some(Duration::from_secs(1))
+                } else {
+                    None
+                }
+            })
+        })
+        .max()
+}
+
+/// Starts an attempt against `keys`, or fails with 429 Too Many Requests if any of them is
+/// currently locked out.
+pub fn begin(
+    store: &dyn AttemptStore,
+    keys: &[String],
+) -> impl Future<Item = (), Error = crate::Error> + Send {
+    store.begin_attempt(keys).and_then(|retry_after| {
+        match retry_after {
+            None => Ok(()),
+            Some(retry_after) => {
+                // round up so clients don't retry just before the lockout ends
+                let seconds = retry_after.as_secs() + 1;
+
+                // attempts turned away here aren't recorded, so they don't extend the lockout
+                Err(crate::Error::Custom(
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::TOO_MANY_REQUESTS)
+                        .header(hyper::header::RETRY_AFTER, seconds.to_string())
+                        .body("Too many failed login attempts".into()),
+                ))
+            }
+        }
+    })
+}
+
+/// Runs for the lifetime of the server, removing counters that no longer matter.
+pub fn run_purge(store: Arc<dyn AttemptStore>) -> impl Future<Item = (), Error = ()> + Send {
+    tokio::timer::Interval::new_interval(PURGE_INTERVAL)
+        .then(|res| -> Result<bool, ()> {
+            match res {
+                Ok(_) => Ok(true),
+                Err(err) => {
+                    eprintln!("Login attempt purge timer failed: {:?}", err);
+                    Ok(!err.is_shutdown())
+                }
+            }
+        })
+        .take_while(|running| Ok(*running))
+        .for_each(move |_| {
+            store.purge().then(|res| {
+                if let Err(crate::Error::Internal(err)) = res {
+                    eprintln!("Failed to purge login attempts: {:?}", err);
+                }
+                Ok(())
+            })
+        })
+}
+
+/// Uses the Postgres store if `LOGIN_ATTEMPT_STORE=postgres`, otherwise the in-memory one.
+pub fn from_env(db_pool: &DbPool) -> Arc<dyn AttemptStore> {
+    match std::env::var("LOGIN_ATTEMPT_STORE")
+        .as_ref()
+        .map(|value| value.as_str())
+    {
+        Ok("postgres") => Arc::new(PostgresAttemptStore::new(db_pool.clone())),
+        Ok("memory") | Err(_) => Arc::new(MemoryAttemptStore::default()),
+        Ok(other) => panic!("Unknown LOGIN_ATTEMPT_STORE: {}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(key: &str, failures: u32, since_last_failure: u64) -> AttemptInfo {
+        AttemptInfo {
+            key: key.to_owned(),
+            failures,
+            since_last_failure: Duration::from_secs(since_last_failure),
+            pending: 0,
+        }
+    }
+
+    fn keys() -> Vec<String> {
+        vec![ip_key("192.0.2.1"), account_key("User@Example.com")]
+    }
+
+    #[test]
+    fn free_attempts_per_key_kind() {
+        assert_eq!(lockout_remaining(&info("email:a@example.com", 4, 0)), None);
+        assert!(lockout_remaining(&info("email:a@example.com", 5, 0)).is_some());
+        assert_eq!(lockout_remaining(&info("ip:192.0.2.1", 19, 0)), None);
+        assert!(lockout_remaining(&info("ip:192.0.2.1", 20, 0)).is_some());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let lockout = |failures| lockout_remaining(&info("email:a@example.com", failures, 0));
+
+        assert_eq!(lockout(5), Some(Duration::from_secs(1)));
+        assert_eq!(lockout(6), Some(Duration::from_secs(2)));
+        assert_eq!(lockout(7), Some(Duration::from_secs(4)));
+        assert_eq!(lockout(14), Some(Duration::from_secs(512)));
+        assert_eq!(lockout(15), Some(MAX_LOCKOUT));
+        assert_eq!(lockout(100), Some(MAX_LOCKOUT));
+    }
+
+    #[test]
+    fn lockout_counts_down_from_the_last_failure() {
+        let key = "email:a@example.com";
+
+        assert_eq!(
+            lockout_remaining(&info(key, 7, 1)),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(lockout_remaining(&info(key, 7, 4)), None);
+    }
+
+    #[test]
+    fn failures_reset_after_window() {
+        let key = "email:a@example.com";
+        let window = RESET_AFTER.as_secs();
+
+        assert_eq!(lockout_remaining(&info(key, 100, window - 1)), None);
+        assert_eq!(max_pending(&info(key, 100, window - 1)), 1);
+        assert_eq!(max_pending(&info(key, 100, window)), ACCOUNT_FREE_ATTEMPTS);
+    }
+
+    #[test]
+    fn concurrent_attempts_limited_to_free_attempts() {
+        let store = MemoryAttemptStore::default();
+        let keys = keys();
+
+        for _ in 0..ACCOUNT_FREE_ATTEMPTS {
+            assert_eq!(store.begin_attempt(&keys).wait().ok().unwrap(), None);
+        }
+        assert_eq!(
+            store.begin_attempt(&keys).wait().ok().unwrap(),
+            Some(Duration::from_secs(1))
+        );
+
+        store.release(&keys).wait().ok().unwrap();
+        assert_eq!(store.begin_attempt(&keys).wait().ok().unwrap(), None);
+    }
+
+    #[test]
+    fn turned_away_attempts_dont_extend_lockout() {
+        let store = MemoryAttemptStore::default();
+        let keys = keys();
+
+        // 16 seconds of lockout, 10 of which have passed
+        let last_failure = Instant::now() - Duration::from_secs(10);
+        store.attempts.lock().unwrap().insert(
+            keys[1].clone(),
+            MemoryEntry {
+                failures: ACCOUNT_FREE_ATTEMPTS + 4,
+                last_failure,
+                pending: 0,
+                last_attempt: last_failure,
+            },
+        );
+
+        for _ in 0..10 {
+            let retry_after = store.begin_attempt(&keys).wait().ok().unwrap();
+            assert!(retry_after.unwrap() <= Duration::from_secs(6));
+        }
+
+        let attempts = store.attempts.lock().unwrap();
+        let entry = &attempts[&keys[1]];
+        assert_eq!(entry.failures, ACCOUNT_FREE_ATTEMPTS + 4);
+        assert_eq!(entry.last_failure, last_failure);
+        assert_eq!(entry.pending, 0);
+        assert!(!attempts.contains_key(&keys[0]));
+    }
+
+    #[test]
+    fn released_attempts_leave_nothing_behind() {
+        let store = MemoryAttemptStore::default();
+        let keys = keys();
+
+        store.begin_attempt(&keys).wait().ok().unwrap();
+        store.release(&keys).wait().ok().unwrap();
+
+        assert!(store.attempts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn eviction_keeps_lockouts() {
+        let store = MemoryAttemptStore::default();
+        let victim = vec![account_key("victim@example.com")];
+
+        for _ in 0..ACCOUNT_FREE_ATTEMPTS {
+            store.begin_attempt(&victim).wait().ok().unwrap();
+            store.record_failure(&victim).wait().ok().unwrap();
+        }
+
+        for i in 0..(MEMORY_STORE_MAX_SIZE * 2) {
+            let keys = vec![account_key(&format!("spray{}@example.com", i))];
+            store.begin_attempt(&keys).wait().ok().unwrap();
+            store.record_failure(&keys).wait().ok().unwrap();
+        }
+
+        let attempts = store.attempts.lock().unwrap();
+        assert!(attempts.len() <= MEMORY_STORE_MAX_SIZE);
+        assert_eq!(attempts[&victim[0]].failures, ACCOUNT_FREE_ATTEMPTS);
+    }
+}
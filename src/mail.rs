@@ -0,0 +1,137 @@
+use futures::Future;
+use std::sync::Arc;
+
+use crate::ErrorWrapper;
+
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, message: Message) -> Box<dyn Future<Item = (), Error = crate::Error> + Send>;
+}
+
+/// Delivers mail through an SMTP relay.
+///
+/// lettre's transport is blocking, so messages are sent on the CPU pool.
+pub struct SmtpMailer {
+    cpupool: Arc<futures_cpupool::CpuPool>,
+    from: String,
+    host: String,
+    port: u16,
+    starttls: bool,
+    credentials: Option<(String, String)>,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, message: Message) -> Box<dyn Future<Item = (), Error = crate::Error> + Send> {
+        let from = self.from.clone();
+        let host = self.host.clone();
+        let port = self.port;
+        let starttls = self.starttls;
+        let credentials = self.credentials.clone();
+
+        Box::new(self.cpupool.spawn_fn(move || {
+            use lettre::Transport;
+
+            let email = lettre_email::EmailBuilder::new()
+                .to(message.to)
+                .from(from)
+                .subject(message.subject)
+                .text(message.body)
+                .build()
+                .map_err(|err| {
+                    crate::Error::internal(ErrorWrapper::Text(format!(
+                        "Failed to build email: {}",
+                        err
+                    )))
+                })?;
+
+            let security = if starttls {
+                let connector = native_tls::TlsConnector::new().map_err(crate::Error::internal)?;
+                lettre::ClientSecurity::Required(
+                    lettre::smtp::client::net::ClientTlsParameters::new(host.clone(), connector),
+                )
+            } else {
+                lettre::ClientSecurity::None
+            };
+
+            let client = lettre::SmtpClient::new((host.as_str(), port), security)
+                .map_err(crate::Error::internal)?;
+
+            let client = match credentials {
+                Some((username, password)) => client.credentials(
+                    lettre::smtp::authentication::Credentials::new(username, password),
+                ),
+                None => client,
+            };
+
+            client
+                .transport()
+                .send(email.into())
+                .map(|_| ())
+                .map_err(crate::Error::internal)
+        }))
+    }
+}
+
+/// Writes messages to a file, or to stdout if no file is configured, instead of sending them.
+pub struct FileMailer {
+    path: Option<String>,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, message: Message) -> Box<dyn Future<Item = (), Error = crate::Error> + Send> {
+        let text = format!(
+            "To: {}\nSubject: {}\n\n{}\n\n",
+            message.to, message.subject, message.body
+        );
+
+        // goes through tokio so the write doesn't block the reactor
+        match &self.path {
+            Some(path) => Box::new(
+                tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path.clone())
+                    .and_then(|file| tokio::io::write_all(file, text))
+                    .map(|_| ())
+                    .map_err(crate::Error::internal),
+            ),
+            None => Box::new(
+                tokio::io::write_all(tokio::io::stdout(), text)
+                    .map(|_| ())
+                    .map_err(crate::Error::internal),
+            ),
+        }
+    }
+}
+
+/// Picks a mailer based on the environment.
+///
+/// SMTP is used if `SMTP_HOST` is set, otherwise messages go to `MAIL_FILE` or stdout.
+pub fn from_env(cpupool: Arc<futures_cpupool::CpuPool>) -> Arc<dyn Mailer> {
+    match std::env::var("SMTP_HOST") {
+        Ok(host) => Arc::new(SmtpMailer {
+            cpupool,
+            from: std::env::var("MAIL_FROM").expect("Missing MAIL_FROM"),
+            host,
+            port: std::env::var("SMTP_PORT")
+                .ok()
+                .map(|value| value.parse().expect("Failed to parse SMTP_PORT"))
+                .unwrap_or(25),
+            starttls: std::env::var("SMTP_INSECURE").is_err(),
+            credentials: std::env::var("SMTP_USERNAME").ok().map(|username| {
+                (
+                    username,
+                    std::env::var("SMTP_PASSWORD").expect("Missing SMTP_PASSWORD"),
+                )
+            }),
+        }),
+        Err(_) => Arc::new(FileMailer {
+            path: std::env::var("MAIL_FILE").ok(),
+        }),
+    }
+}
@@ -2,7 +2,16 @@ use futures::{Future, Stream};
 use serde_derive::{Deserialize, Serialize};
 use std::sync::{Arc, RwLock};
 
+mod api_keys;
+mod client_ip;
+mod login_attempts;
+mod mail;
+mod passwords;
+mod path_mode;
 mod routes;
+mod schedule;
+mod totp;
+mod validation;
 
 pub enum Error {
     NotFound,
@@ -61,6 +70,12 @@ pub struct TierInfo {
 
 pub struct Settings {
     pub free_visits: i32,
+    pub login_max_age: Option<i32>,
+    pub login_idle_timeout: Option<i32>,
+    pub require_verified_email: bool,
+    pub hash_params: passwords::HashParams,
+    pub client_ip_source: client_ip::ClientIPSource,
+    pub destination_denylist: validation::DestinationDenylist,
     pub frontend_host: Option<String>,
     pub redirect_host: Option<String>,
     pub stripe_secret_key: Option<String>,
@@ -72,17 +87,45 @@ pub struct ServerState {
     pub http_client: HttpClient,
     pub settings: Arc<Settings>,
     pub tiers: Arc<RwLock<Vec<TierInfo>>>,
+    pub mailer: Arc<dyn mail::Mailer>,
+    pub login_attempts: Arc<dyn login_attempts::AttemptStore>,
+    /// Checked against when logging in to an unknown account, so it takes as long as a real one.
+    pub dummy_passhash: Arc<String>,
+    pub resolver: trust_dns_resolver::AsyncResolver,
 }
 
 impl ServerState {
-    pub fn new(settings: Settings) -> ServerState {
+    pub fn new(
+        settings: Settings,
+        mailer: Arc<dyn mail::Mailer>,
+        login_attempts: Arc<dyn login_attempts::AttemptStore>,
+        resolver: trust_dns_resolver::AsyncResolver,
+    ) -> ServerState {
+        let dummy_passhash =
+            passwords::hash(&uuid::Uuid::new_v4().to_string(), &settings.hash_params)
+                .unwrap_or_else(|_| panic!("Failed to generate dummy password hash"));
+
         Self {
             http_client: Arc::new(hyper::Client::builder().build(
                 hyper_tls::HttpsConnector::new(4).expect("TLS client initialization failed"),
             )),
             settings: Arc::new(settings),
             tiers: Arc::new(RwLock::new(Vec::new())),
+            mailer,
+            login_attempts,
+            dummy_passhash: Arc::new(dummy_passhash),
+            resolver,
+        }
+    }
+}
+
+/// Whether a database error was caused by a unique constraint, for mapping to 409 Conflict.
+pub fn is_unique_violation(err: &bb8::RunError<tokio_postgres::Error>) -> bool {
+    match err {
+        bb8::RunError::User(err) => {
+            err.code() == Some(&tokio_postgres::error::SqlState::UNIQUE_VIOLATION)
         }
+        bb8::RunError::TimedOut => false,
     }
 }
 
@@ -115,14 +158,11 @@ impl UserID {
     }
 }
 
-pub fn rd_login(
-    db_pool: &DbPool,
-    req: &hyper::Request<hyper::Body>,
-) -> impl Future<Item = Option<UserID>, Error = Error> + Send {
+pub fn get_bearer_token(req: &hyper::Request<hyper::Body>) -> Option<Result<String, Error>> {
     use headers::Header;
 
     let value = req.headers().get(hyper::header::AUTHORIZATION);
-    let value = value.map(|value| {
+    value.map(|value| {
         headers::Authorization::<headers::authorization::Bearer>::decode(
             &mut vec![value].into_iter(),
         )
@@ -134,46 +174,125 @@ pub fn rd_login(
             )
         })
         .map(|value| value.0.token().to_owned())
-    });
-    let value = value.map(|src| {
+    })
+}
+
+pub fn get_auth_token(req: &hyper::Request<hyper::Body>) -> Option<Result<uuid::Uuid, Error>> {
+    get_bearer_token(req).map(|src| {
         src.and_then(|src| {
             src.parse::<uuid::Uuid>()
                 .map_err(|err| Error::Internal(Box::new(err)))
         })
-    });
-    match value {
-        Some(Ok(token)) => futures::future::Either::A(
+    })
+}
+
+/// Resolves the bearer token on a request to a user.
+///
+/// Tokens older than `login_max_age` seconds, or unused for longer than `login_idle_timeout`
+/// seconds, are rejected. Each successful lookup renews the token's `last_used` timestamp.
+///
+/// API keys are only accepted if they were granted `scope`. A `scope` of `None` means the
+/// endpoint requires a full login session.
+pub fn rd_login(
+    db_pool: &DbPool,
+    settings: &Settings,
+    req: &hyper::Request<hyper::Body>,
+    scope: Option<api_keys::Scope>,
+) -> impl Future<Item = Option<UserID>, Error = Error> + Send {
+    let max_age = settings.login_max_age;
+    let idle_timeout = settings.login_idle_timeout;
+
+    let token = match get_bearer_token(req) {
+        Some(Ok(token)) => token,
+        None | Some(Err(_)) => return futures::future::Either::B(futures::future::ok(None)),
+    };
+
+    if token.starts_with(api_keys::PREFIX) {
+        let key_hash = api_keys::hash(&token);
+
+        return futures::future::Either::A(futures::future::Either::B(
             db_pool
                 .run(move |mut conn| {
-                    conn.prepare("SELECT user_id FROM logins WHERE token=$1")
+                    conn.prepare("UPDATE api_keys SET last_used=localtimestamp WHERE key_hash=$1 RETURNING user_id, scopes")
                         .then(|res| tack_on(res, conn))
                         .and_then(move |(stmt, mut conn)| {
-                            conn.query(&stmt, &[&token])
+                            conn.query(&stmt, &[&key_hash])
                                 .into_future()
                                 .map(|(res, _)| res)
                                 .map_err(|(err, _)| err)
+                                .map(|row| row.map(|row| (row.get::<_, i32>(0), row.get::<_, Vec<String>>(1))))
                                 .then(|res| tack_on(res, conn))
                         })
                 })
                 .map_err(ErrorWrapper::from)
                 .map_err(|err| Error::Internal(Box::new(err)))
-                .and_then(|row| {
-                    row.ok_or_else(|| {
-                        Error::Custom(
+                .and_then(move |row| match row {
+                    Some((user_id, scopes)) => match scope {
+                        Some(scope) if scopes.iter().any(|value| value == scope.as_str()) => {
+                            Ok(Some(UserID(user_id)))
+                        }
+                        _ => Err(Error::Custom(
                             hyper::Response::builder()
-                                .status(hyper::StatusCode::BAD_REQUEST)
-                                .body("Unrecognized authentication token".into()),
-                        )
-                    })
-                })
-                .and_then(|row| {
-                    let user_id: i32 = row.get(0);
-                    let user_id = UserID(user_id);
-                    Ok(Some(user_id))
+                                .status(hyper::StatusCode::FORBIDDEN)
+                                .body("This API key is not allowed to access this endpoint".into()),
+                        )),
+                    },
+                    None => Err(Error::Custom(
+                        hyper::Response::builder()
+                            .status(hyper::StatusCode::UNAUTHORIZED)
+                            .body("Unrecognized authentication token".into()),
+                    )),
                 }),
-        ),
-        None | Some(Err(_)) => futures::future::Either::B(futures::future::ok(None)),
+        ));
     }
+
+    let token = match token.parse::<uuid::Uuid>() {
+        Ok(token) => token,
+        Err(_) => return futures::future::Either::B(futures::future::ok(None)),
+    };
+
+    futures::future::Either::A(futures::future::Either::A(
+        db_pool
+            .run(move |mut conn| {
+                conn.prepare("SELECT user_id, ($2::INTEGER IS NOT NULL AND created < localtimestamp - $2::INTEGER * INTERVAL '1 second') OR ($3::INTEGER IS NOT NULL AND COALESCE(last_used, created) < localtimestamp - $3::INTEGER * INTERVAL '1 second') FROM logins WHERE token=$1")
+                    .then(|res| tack_on(res, conn))
+                    .and_then(move |(stmt, mut conn)| {
+                        conn.query(&stmt, &[&token, &max_age, &idle_timeout])
+                            .into_future()
+                            .map(|(res, _)| res)
+                            .map_err(|(err, _)| err)
+                            .map(|row| row.map(|row| (row.get::<_, i32>(0), row.get::<_, bool>(1))))
+                            .then(|res| tack_on(res, conn))
+                    })
+                    .and_then(move |(row, mut conn)| match row {
+                        Some((_, false)) => futures::future::Either::A(
+                            conn.prepare("UPDATE logins SET last_used=localtimestamp WHERE token=$1")
+                                .then(|res| tack_on(res, conn))
+                                .and_then(move |(stmt, mut conn)| {
+                                    conn.execute(&stmt, &[&token])
+                                        .map(move |_| row)
+                                        .then(|res| tack_on(res, conn))
+                                }),
+                        ),
+                        _ => futures::future::Either::B(futures::future::ok((row, conn))),
+                    })
+            })
+            .map_err(ErrorWrapper::from)
+            .map_err(|err| Error::Internal(Box::new(err)))
+            .and_then(|row| match row {
+                Some((user_id, false)) => Ok(Some(UserID(user_id))),
+                Some((_, true)) => Err(Error::Custom(
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::UNAUTHORIZED)
+                        .body("Authentication token expired".into()),
+                )),
+                None => Err(Error::Custom(
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::UNAUTHORIZED)
+                        .body("Unrecognized authentication token".into()),
+                )),
+            }),
+    ))
 }
 
 fn consume_path<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
@@ -190,6 +309,7 @@ fn consume_path_segment(path: &str) -> Option<(&str, &str)> {
 
 fn handle_request(
     req: hyper::Request<hyper::Body>,
+    remote_addr: std::net::SocketAddr,
     cpupool: &Arc<futures_cpupool::CpuPool>,
     db_pool: &DbPool,
     server_state: &ServerState,
@@ -204,13 +324,17 @@ fn handle_request(
     }
 
     let result = if let Some(path) = consume_path(path, "logins/") {
-        routes::logins(cpupool, db_pool, req, path)
+        routes::logins(cpupool, db_pool, server_state, req, remote_addr, path)
     } else if let Some(path) = consume_path(path, "redirects/") {
-        routes::redirects(db_pool, req, path)
+        routes::redirects(db_pool, server_state, req, path)
     } else if let Some(path) = consume_path(path, "users/") {
         routes::users(cpupool, db_pool, server_state, req, path)
     } else if let Some(path) = consume_path(path, "subscription_tiers/") {
         routes::subscription_tiers(server_state, req, path)
+    } else if let Some(path) = consume_path(path, "email_verifications/") {
+        routes::email_verifications(db_pool, req, path)
+    } else if let Some(path) = consume_path(path, "password_resets/") {
+        routes::password_resets(cpupool, db_pool, server_state, req, path)
     } else if let Some(path) = consume_path(path, "settings/") {
         routes::settings(server_state, req, path)
     } else {
@@ -267,6 +391,10 @@ fn main() {
 
     tokio::run(futures::lazy(move || {
         let cpupool = Arc::new(futures_cpupool::CpuPool::new_num_cpus());
+        let mailer = mail::from_env(cpupool.clone());
+        let (resolver, resolver_background) = trust_dns_resolver::AsyncResolver::from_system_conf()
+            .expect("Failed to read DNS configuration");
+        tokio::spawn(resolver_background);
         bb8::Pool::builder()
             .build(bb8_postgres::PostgresConnectionManager::new(
                 database_url,
@@ -286,6 +414,28 @@ fn main() {
                                     .map(|row| {
                                         row.map(|row| Settings {
                                             free_visits: row.get(0),
+                                            login_max_age: std::env::var("LOGIN_MAX_AGE").ok().map(
+                                                |value| {
+                                                    value
+                                                        .parse()
+                                                        .expect("Failed to parse LOGIN_MAX_AGE")
+                                                },
+                                            ),
+                                            login_idle_timeout: std::env::var("LOGIN_IDLE_TIMEOUT")
+                                                .ok()
+                                                .map(|value| {
+                                                    value.parse().expect(
+                                                        "Failed to parse LOGIN_IDLE_TIMEOUT",
+                                                    )
+                                                }),
+                                            require_verified_email: std::env::var(
+                                                "REQUIRE_VERIFIED_EMAIL",
+                                            )
+                                            .is_ok(),
+                                            hash_params: passwords::HashParams::from_env(),
+                                            client_ip_source: client_ip::ClientIPSource::from_env(),
+                                            destination_denylist:
+                                                validation::DestinationDenylist::from_env(),
                                             frontend_host: std::env::var("FRONTEND_HOST").ok(),
                                             redirect_host: std::env::var("REDIRECT_HOST").ok(),
                                             stripe_publishable_key: std::env::var(
@@ -301,25 +451,38 @@ fn main() {
                     })
                     .map_err(|err| panic!("Failed to retrieve settings: {:?}", err))
                     .map(|settings| match settings {
-                        Some(settings) => (db_pool, ServerState::new(settings)),
+                        Some(settings) => {
+                            let login_attempts = login_attempts::from_env(&db_pool);
+                            (
+                                db_pool,
+                                ServerState::new(settings, mailer, login_attempts, resolver),
+                            )
+                        }
                         None => panic!("Failed to retrieve settings: no row returned"),
                     })
             })
             .and_then(move |(db_pool, server_state)| {
                 tokio::spawn(retrieve_plans(&db_pool, server_state.clone()));
+                tokio::spawn(schedule::run(db_pool.clone()));
+                tokio::spawn(login_attempts::run_purge(
+                    server_state.login_attempts.clone(),
+                ));
 
                 hyper::Server::bind(&std::net::SocketAddr::from((
                     std::net::Ipv6Addr::UNSPECIFIED,
                     port,
                 )))
-                .serve(move || {
-                    let db_pool = db_pool.clone();
-                    let cpupool = cpupool.clone();
-                    let server_state = server_state.clone();
-                    hyper::service::service_fn(move |req| {
-                        handle_request(req, &cpupool, &db_pool, &server_state)
-                    })
-                })
+                .serve(hyper::service::make_service_fn(
+                    move |socket: &hyper::server::conn::AddrStream| {
+                        let remote_addr = socket.remote_addr();
+                        let db_pool = db_pool.clone();
+                        let cpupool = cpupool.clone();
+                        let server_state = server_state.clone();
+                        Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
+                            handle_request(req, remote_addr, &cpupool, &db_pool, &server_state)
+                        }))
+                    },
+                ))
                 .map_err(|err| panic!("Server execution failed: {:?}", err))
             })
     }))
@@ -0,0 +1,230 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Bcrypt,
+    Argon2id,
+}
+
+/// Parameters used for new hashes. Existing hashes made with other parameters still verify, and
+/// are upgraded on the next successful login.
+#[derive(Debug, Clone)]
+pub struct HashParams {
+    pub algorithm: Algorithm,
+    pub bcrypt_cost: u32,
+    /// In KiB.
+    pub argon2_memory_cost: u32,
+    pub argon2_time_cost: u32,
+    pub argon2_parallelism: u32,
+}
+
+impl Default for HashParams {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Bcrypt,
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            argon2_memory_cost: 19 * 1024,
+            argon2_time_cost: 2,
+            argon2_parallelism: 1,
+        }
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("Failed to parse {}", name)),
+        Err(_) => default,
+    }
+}
+
+impl HashParams {
+    /// Reads `PASSWORD_HASH_ALGORITHM` (`bcrypt` or `argon2id`), `BCRYPT_COST`,
+    /// `ARGON2_MEMORY_COST`, `ARGON2_TIME_COST` and `ARGON2_PARALLELISM`.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+
+        Self {
+            algorithm: match std::env::var("PASSWORD_HASH_ALGORITHM")
+                .as_ref()
+                .map(|value| value.as_str())
+            {
+                Ok("bcrypt") | Err(_) => Algorithm::Bcrypt,
+                Ok("argon2id") => Algorithm::Argon2id,
+                Ok(other) => panic!("Unknown PASSWORD_HASH_ALGORITHM: {}", other),
+            },
+            bcrypt_cost: parse_env("BCRYPT_COST", default.bcrypt_cost),
+            argon2_memory_cost: parse_env("ARGON2_MEMORY_COST", default.argon2_memory_cost),
+            argon2_time_cost: parse_env("ARGON2_TIME_COST", default.argon2_time_cost),
+            argon2_parallelism: parse_env("ARGON2_PARALLELISM", default.argon2_parallelism),
+        }
+    }
+
+    fn argon2_config(&self) -> argon2::Config {
+        argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            version: argon2::Version::Version13,
+            mem_cost: self.argon2_memory_cost,
+            time_cost: self.argon2_time_cost,
+            lanes: self.argon2_parallelism,
+            thread_mode: argon2::ThreadMode::Sequential,
+            secret: &[],
+            ad: &[],
+            hash_length: 32,
+        }
+    }
+}
+
+/// Blocks for a while, so should be run on the CPU pool.
+pub fn hash(password: &str, params: &HashParams) -> Result<String, crate::Error> {
+    match params.algorithm {
+        Algorithm::Bcrypt => {
+            bcrypt::hash(password, params.bcrypt_cost).map_err(crate::Error::internal)
+        }
+        Algorithm::Argon2id => {
+            let salt = rand::random::<[u8; 16]>();
+            argon2::hash_encoded(password.as_bytes(), &salt, &params.argon2_config())
+                .map_err(crate::Error::internal)
+        }
+    }
+}
+
+/// Checks a password against a hash in either supported format. Also blocks.
+pub fn verify(password: &str, passhash: &str) -> Result<bool, crate::Error> {
+    if passhash.starts_with("$argon2") {
+        argon2::verify_encoded(passhash, password.as_bytes()).map_err(crate::Error::internal)
+    } else {
+        bcrypt::verify(password, passhash).map_err(crate::Error::internal)
+    }
+}
+
+/// Whether `passhash` was made with a different algorithm or parameters than `params`.
+pub fn needs_rehash(passhash: &str, params: &HashParams) -> bool {
+    // both formats look like $<id>$<params>$...
+    let mut parts = passhash.split('$').skip(1);
+    let id = parts.next();
+
+    match params.algorithm {
+        Algorithm::Bcrypt => {
+            let is_bcrypt = match id {
+                Some("2a") | Some("2b") | Some("2x") | Some("2y") => true,
+                _ => false,
+            };
+
+            let expected = format!("{:02}", params.bcrypt_cost);
+
+            !is_bcrypt || parts.next() != Some(expected.as_str())
+        }
+        Algorithm::Argon2id => {
+            let expected = format!(
+                "m={},t={},p={}",
+                params.argon2_memory_cost, params.argon2_time_cost, params.argon2_parallelism
+            );
+
+            id != Some("argon2id")
+                || parts.next() != Some("v=19")
+                || parts.next() != Some(expected.as_str())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BCRYPT_HASH: &str = "$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW";
+    const ARGON2ID_HASH: &str =
+        "$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHRzb21lc2FsdA$3Zt0AmNdfd9ZOxu8MC8z6AvHfvGnHzFNS3m0lF9ObaE";
+
+    fn bcrypt_params(cost: u32) -> HashParams {
+        HashParams {
+            bcrypt_cost: cost,
+            ..HashParams::default()
+        }
+    }
+
+    fn argon2id_params(memory_cost: u32, time_cost: u32, parallelism: u32) -> HashParams {
+        HashParams {
+            algorithm: Algorithm::Argon2id,
+            argon2_memory_cost: memory_cost,
+            argon2_time_cost: time_cost,
+            argon2_parallelism: parallelism,
+            ..HashParams::default()
+        }
+    }
+
+    #[test]
+    fn bcrypt_cost_compared() {
+        assert!(!needs_rehash(BCRYPT_HASH, &bcrypt_params(12)));
+        assert!(needs_rehash(BCRYPT_HASH, &bcrypt_params(13)));
+        assert!(needs_rehash(BCRYPT_HASH, &bcrypt_params(10)));
+
+        for prefix in &["2a", "2x", "2y"] {
+            let hash = BCRYPT_HASH.replacen("2b", prefix, 1);
+            assert!(!needs_rehash(&hash, &bcrypt_params(12)), "{}", hash);
+        }
+
+        let hash = BCRYPT_HASH.replacen("$12$", "$09$", 1);
+        assert!(!needs_rehash(&hash, &bcrypt_params(9)));
+    }
+
+    #[test]
+    fn algorithm_switch_detected() {
+        assert!(needs_rehash(BCRYPT_HASH, &argon2id_params(19 * 1024, 2, 1)));
+        assert!(needs_rehash(ARGON2ID_HASH, &bcrypt_params(12)));
+    }
+
+    #[test]
+    fn argon2_params_compared() {
+        assert!(!needs_rehash(
+            ARGON2ID_HASH,
+            &argon2id_params(19 * 1024, 2, 1)
+        ));
+        assert!(needs_rehash(
+            ARGON2ID_HASH,
+            &argon2id_params(64 * 1024, 2, 1)
+        ));
+        assert!(needs_rehash(
+            ARGON2ID_HASH,
+            &argon2id_params(19 * 1024, 3, 1)
+        ));
+        assert!(needs_rehash(
+            ARGON2ID_HASH,
+            &argon2id_params(19 * 1024, 2, 4)
+        ));
+
+        let argon2i = ARGON2ID_HASH.replacen("argon2id", "argon2i", 1);
+        assert!(needs_rehash(&argon2i, &argon2id_params(19 * 1024, 2, 1)));
+
+        let old_version = ARGON2ID_HASH.replacen("v=19", "v=16", 1);
+        assert!(needs_rehash(
+            &old_version,
+            &argon2id_params(19 * 1024, 2, 1)
+        ));
+    }
+
+    #[test]
+    fn malformed_hash_needs_rehash() {
+        for hash in &["", "plaintext", "$", "$2b$", "$argon2id$v=19"] {
+            assert!(needs_rehash(hash, &bcrypt_params(12)), "{}", hash);
+            assert!(
+                needs_rehash(hash, &argon2id_params(19 * 1024, 2, 1)),
+                "{}",
+                hash
+            );
+        }
+    }
+
+    #[test]
+    fn new_hashes_match_their_params() {
+        let params = bcrypt_params(4);
+        let passhash = hash("hunter2", &params).ok().unwrap();
+        assert!(!needs_rehash(&passhash, &params));
+        assert!(verify("hunter2", &passhash).ok().unwrap());
+
+        let params = argon2id_params(64, 1, 1);
+        let passhash = hash("hunter2", &params).ok().unwrap();
+        assert!(!needs_rehash(&passhash, &params));
+        assert!(verify("hunter2", &passhash).ok().unwrap());
+        assert!(!verify("hunter3", &passhash).ok().unwrap());
+    }
+}
@@ -0,0 +1,259 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// Placeholders available to destinations in `PathMode::Template`.
+pub const PATH_PLACEHOLDER: &str = "{path}";
+pub const QUERY_PLACEHOLDER: &str = "{query}";
+
+/// Placeholder for the label matched by a wildcard host, available in any mode.
+pub const LABEL_PLACEHOLDER: &str = "{label}";
+
+/// What happens to the request path and query when redirecting.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PathMode {
+    /// Always redirect to the destination as-is.
+    #[serde(rename = "discard")]
+    Discard,
+    /// Append the request path and query to the destination.
+    #[serde(rename = "append")]
+    Append,
+    /// Substitute the request path and query into placeholders in the destination.
+    #[serde(rename = "template")]
+    Template,
+}
+
+impl Default for PathMode {
+    fn default() -> Self {
+        PathMode::Discard
+    }
+}
+
+impl PathMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PathMode::Discard => "discard",
+            PathMode::Append => "append",
+            PathMode::Template => "template",
+        }
+    }
+
+    /// Reads the `path_mode` column, which is constrained to the values from `as_str`.
+    pub fn from_column(value: &str) -> Self {
+        value.parse().unwrap_or_default()
+    }
+
+    /// Builds the location a request is redirected to. `path` is the request path, starting with
+    /// `/`, and `label` is the part of the request host matched by a wildcard.
+    pub fn resolve(
+        self,
+        destination: &str,
+        path: &str,
+        query: Option<&str>,
+        label: Option<&str>,
+    ) -> String {
+        let destination = match label {
+            Some(label) => destination.replace(LABEL_PLACEHOLDER, label),
+            None => destination.to_owned(),
+        };
+
+        match self {
+            PathMode::Discard => destination,
+            PathMode::Append => append(&destination, path, query),
+            PathMode::Template => fill_template(&destination, path, query),
+        }
+    }
+}
+
+/// Appends the request path and query to a destination, without doubling the `/` between them,
+/// and after any query the destination already has.
+fn append(destination: &str, path: &str, query: Option<&str>) -> String {
+    let (base, own_query) = match destination.find('?') {
+        Some(idx) => (&destination[..idx], Some(&destination[(idx + 1)..])),
+        None => (destination, None),
+    };
+
+    let mut result = base.trim_end_matches('/').to_owned();
+    result.push_str(path);
+
+    let queries: Vec<&str> = own_query
+        .into_iter()
+        .chain(query)
+        .filter(|query| !query.is_empty())
+        .collect();
+    if !queries.is_empty() {
+        result.push('?');
+        result.push_str(&queries.join("&"));
+    }
+
+    result
+}
+
+/// Substitutes the request path and query into the placeholders of a template destination. The
+/// request itself is never searched for placeholders, and a `/` right before `{path}` isn't doubled.
+pub fn fill_template(destination: &str, path: &str, query: Option<&str>) -> String {
+    let mut result = String::with_capacity(destination.len() + path.len());
+    let mut rest = destination;
+
+    loop {
+        let next = [PATH_PLACEHOLDER, QUERY_PLACEHOLDER]
+            .iter()
+            .filter_map(|placeholder| rest.find(placeholder).map(|idx| (idx, *placeholder)))
+            .min();
+        let (idx, placeholder) = match next {
+            Some(next) => next,
+            None => break,
+        };
+
+        result.push_str(&rest[..idx]);
+        if placeholder == PATH_PLACEHOLDER {
+            if result.ends_with('/') && path.starts_with('/') {
+                result.push_str(&path[1..]);
+            } else {
+                result.push_str(path);
+            }
+        } else {
+            result.push_str(query.unwrap_or(""));
+        }
+        rest = &rest[(idx + placeholder.len())..];
+    }
+    result.push_str(rest);
+
+    // left behind by `?{query}` when the request has none
+    if result.ends_with('?') {
+        result.pop();
+    }
+
+    result
+}
+
+impl std::str::FromStr for PathMode {
+    type Err = ();
+    fn from_str(src: &str) -> Result<PathMode, Self::Err> {
+        match src {
+            "discard" => Ok(PathMode::Discard),
+            "append" => Ok(PathMode::Append),
+            "template" => Ok(PathMode::Template),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discard_ignores_the_request() {
+        assert_eq!(
+            PathMode::Discard.resolve(
+                "https://new.example/landing",
+                "/old/page",
+                Some("x=1"),
+                None
+            ),
+            "https://new.example/landing"
+        );
+    }
+
+    #[test]
+    fn append_joins_paths() {
+        let resolve = |destination, path| PathMode::Append.resolve(destination, path, None, None);
+
+        assert_eq!(
+            resolve("https://new.example", "/foo"),
+            "https://new.example/foo"
+        );
+        assert_eq!(
+            resolve("https://new.example/", "/foo"),
+            "https://new.example/foo"
+        );
+        assert_eq!(resolve("https://new.example/", "/"), "https://new.example/");
+        assert_eq!(
+            resolve("https://new.example/base/", "/foo/bar"),
+            "https://new.example/base/foo/bar"
+        );
+        assert_eq!(
+            resolve("https://new.example/base", "/foo"),
+            "https://new.example/base/foo"
+        );
+    }
+
+    #[test]
+    fn append_joins_queries() {
+        assert_eq!(
+            PathMode::Append.resolve("https://new.example", "/foo", Some("x=1"), None),
+            "https://new.example/foo?x=1"
+        );
+        assert_eq!(
+            PathMode::Append.resolve("https://new.example/", "/foo", Some(""), None),
+            "https://new.example/foo"
+        );
+        assert_eq!(
+            PathMode::Append.resolve("https://new.example/?ref=old", "/foo", Some("x=1"), None),
+            "https://new.example/foo?ref=old&x=1"
+        );
+        assert_eq!(
+            PathMode::Append.resolve("https://new.example/?ref=old", "/foo", None, None),
+            "https://new.example/foo?ref=old"
+        );
+    }
+
+    #[test]
+    fn template_substitutes_placeholders() {
+        let resolve =
+            |destination, query| PathMode::Template.resolve(destination, "/foo/bar", query, None);
+
+        assert_eq!(
+            resolve("https://new.example{path}?{query}", Some("x=1")),
+            "https://new.example/foo/bar?x=1"
+        );
+        assert_eq!(
+            resolve("https://new.example{path}?{query}", None),
+            "https://new.example/foo/bar"
+        );
+        assert_eq!(
+            resolve("https://new.example/docs/{path}", None),
+            "https://new.example/docs/foo/bar"
+        );
+        assert_eq!(
+            resolve(
+                "https://new.example/search?from={path}&{query}",
+                Some("q=a")
+            ),
+            "https://new.example/search?from=/foo/bar&q=a"
+        );
+    }
+
+    #[test]
+    fn template_ignores_placeholders_in_the_request() {
+        assert_eq!(
+            PathMode::Template.resolve(
+                "https://new.example{path}?{query}",
+                "/%7Bquery%7D/{query}",
+                Some("{path}"),
+                None
+            ),
+            "https://new.example/%7Bquery%7D/{query}?{path}"
+        );
+    }
+
+    #[test]
+    fn label_is_substituted_in_any_mode() {
+        assert_eq!(
+            PathMode::Discard.resolve("https://{label}.new.example/", "/foo", None, Some("eu")),
+            "https://eu.new.example/"
+        );
+        assert_eq!(
+            PathMode::Append.resolve("https://new.example/{label}", "/foo", None, Some("eu")),
+            "https://new.example/eu/foo"
+        );
+        assert_eq!(
+            PathMode::Template.resolve(
+                "https://new.example/{label}{path}",
+                "/foo",
+                None,
+                Some("eu")
+            ),
+            "https://new.example/eu/foo"
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use futures::{Future, IntoFuture, Stream};
+
+use crate::{tack_on, DbPool, ErrorWrapper, ServerState, UserID};
+
+/// Creates a verification token for `email` and mails a link for it to that address. With
+/// `email_change`, confirming it moves the account to `email`; otherwise it only verifies `email`
+/// while that is still the account's address.
+pub fn send_verification(
+    db_pool: &DbPool,
+    server_state: &ServerState,
+    user_id: UserID,
+    email: String,
+    email_change: bool,
+) -> impl Future<Item = (), Error = crate::Error> + Send {
+    let db_pool = db_pool.clone();
+    let mailer = server_state.mailer.clone();
+
+    server_state
+        .settings
+        .frontend_host
+        .clone()
+        .ok_or_else(|| {
+            crate::Error::internal(ErrorWrapper::Text("Missing frontend host".to_owned()))
+        })
+        .into_future()
+        .and_then(move |frontend_host| {
+            let token = uuid::Uuid::new_v4();
+            let to = email.clone();
+
+            db_pool
+                .run(move |mut conn| {
+                    conn.prepare("INSERT INTO email_verifications (token, user_id, email, email_change, created) VALUES ($1, $2, $3, $4, localtimestamp)")
+                        .then(|res| tack_on(res, conn))
+                        .and_then(move |(stmt, mut conn)| {
+                            conn.execute(&stmt, &[&token, &user_id.to_raw(), &email, &email_change])
+                                .then(|res| tack_on(res, conn))
+                        })
+                })
+                .map_err(ErrorWrapper::from)
+                .map_err(crate::Error::internal)
+                .and_then(move |_| {
+                    mailer.send(crate::mail::Message {
+                        to,
+                        subject: "Verify your email address".to_owned(),
+                        body: format!("To verify your email address, open this link within the next day:\n\n{}/verifyEmail?token={}", frontend_host, token),
+                    })
+                })
+        })
+}
+
+pub fn email_verifications(
+    db_pool: &DbPool,
+    req: hyper::Request<hyper::Body>,
+    path: &str,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    if path.is_empty() {
+        Box::new(futures::future::err(crate::Error::InvalidMethod))
+    } else if let Some((segment, path)) = crate::consume_path_segment(path) {
+        if !path.is_empty() {
+            return Box::new(futures::future::err(crate::Error::NotFound));
+        }
+
+        let token = match segment.parse::<uuid::Uuid>() {
+            Ok(token) => token,
+            Err(_err) => {
+                return Box::new(futures::future::err(crate::Error::Custom(
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::BAD_REQUEST)
+                        .body("Invalid email verification token".into()),
+                )));
+            }
+        };
+
+        match *req.method() {
+            hyper::Method::POST => {
+                // also applies pending email changes, since those are verified the same way. Other
+                // tokens of the same kind are dropped, and a leftover verification for a previous
+                // address does nothing.
+                Box::new(db_pool.run(move |mut conn| {
+                    conn.prepare("WITH verification AS (DELETE FROM email_verifications WHERE token=$1 RETURNING user_id, email, email_change, created), updated AS (UPDATE users SET email=verification.email, verified=TRUE FROM verification WHERE users.id=verification.user_id AND verification.created > localtimestamp - INTERVAL '1 day' AND (verification.email_change OR users.email=verification.email) RETURNING users.id), stale AS (DELETE FROM email_verifications USING verification WHERE email_verifications.token<>$1 AND email_verifications.user_id IN (SELECT id FROM updated) AND email_verifications.email_change=verification.email_change) SELECT id FROM updated")
+                        .then(|res| tack_on(res, conn))
+                        .and_then(move |(stmt, mut conn)| {
+                            conn.query(&stmt, &[&token])
+                                .into_future()
+                                .map(|(res, _)| res)
+                                .map_err(|(err, _)| err)
+                                .then(|res| tack_on(res, conn))
+                        })
+                })
+                         .map_err(|err| {
+                             if crate::is_unique_violation(&err) {
+                                 crate::Error::Custom(hyper::Response::builder()
+                                                      .status(hyper::StatusCode::CONFLICT)
+                                                      .body("That email address is already in use".into()))
+                             } else {
+                                 crate::Error::internal(ErrorWrapper::from(err))
+                             }
+                         })
+                         .and_then(|row| {
+                             match row {
+                                 Some(_) => {
+                                     hyper::Response::builder()
+                                         .status(hyper::StatusCode::NO_CONTENT)
+                                         .body(hyper::Body::empty())
+                                         .map_err(crate::Error::internal)
+                                 }
+                                 None => {
+                                     Err(crate::Error::Custom(hyper::Response::builder()
+                                                              .status(hyper::StatusCode::BAD_REQUEST)
+                                                              .body("Invalid or expired email verification token".into())))
+                                 }
+                             }
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else {
+        Box::new(futures::future::err(crate::Error::NotFound))
+    }
+}
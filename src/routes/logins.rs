@@ -1,19 +1,79 @@
-use futures::{Future, Stream};
+use futures::{Future, IntoFuture, Stream};
 use serde_derive::Deserialize;
 use std::sync::Arc;
 
-use crate::{tack_on, DbPool, ErrorWrapper};
+use crate::login_attempts::{self, AttemptStore};
+use crate::{tack_on, DbPool, ErrorWrapper, ServerState};
 
 #[derive(Debug, Deserialize)]
 struct LoginReqBody {
     email: String,
     password: String,
+    totp_code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+/// Finishes the attempt as a failure before passing on the error.
+fn attempt_failed<T: Send + 'static>(
+    attempts: &dyn AttemptStore,
+    keys: &[String],
+    err: crate::Error,
+) -> Box<dyn Future<Item = T, Error = crate::Error> + Send> {
+    Box::new(attempts.record_failure(keys).then(move |_| Err(err)))
+}
+
+/// Finishes the attempt without counting it before passing on the error, for outcomes that aren't
+/// a wrong guess.
+fn attempt_released<T: Send + 'static>(
+    attempts: &dyn AttemptStore,
+    keys: &[String],
+    err: crate::Error,
+) -> Box<dyn Future<Item = T, Error = crate::Error> + Send> {
+    Box::new(attempts.release(keys).then(move |_| Err(err)))
+}
+
+/// Replaces a hash made with outdated parameters. Failures are only logged, since the old hash
+/// still works.
+fn rehash(
+    cpupool: &futures_cpupool::CpuPool,
+    db_pool: &DbPool,
+    user_id: i32,
+    password: String,
+    old_passhash: String,
+    hash_params: crate::passwords::HashParams,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let db_pool = db_pool.clone();
+
+    cpupool
+        .spawn_fn(move || crate::passwords::hash(&password, &hash_params))
+        .and_then(move |passhash| {
+            db_pool
+                .run(move |mut conn| {
+                    // skipped if the password was changed in the meantime
+                    conn.prepare("UPDATE users SET passhash=$1 WHERE id=$2 AND passhash=$3")
+                        .then(|res| tack_on(res, conn))
+                        .and_then(move |(stmt, mut conn)| {
+                            conn.execute(&stmt, &[&passhash, &user_id, &old_passhash])
+                                .then(|res| tack_on(res, conn))
+                        })
+                })
+                .map_err(ErrorWrapper::from)
+                .map_err(crate::Error::internal)
+        })
+        .map(|_| ())
+        .map_err(|err| {
+            if let crate::Error::Internal(err) = err {
+                eprintln!("Failed to rehash password: {:?}", err);
+            }
+        })
 }
 
 pub fn logins(
     cpupool: &Arc<futures_cpupool::CpuPool>,
     db_pool: &DbPool,
+    server_state: &ServerState,
     req: hyper::Request<hyper::Body>,
+    remote_addr: std::net::SocketAddr,
     path: &str,
 ) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
     if path.is_empty() {
@@ -21,6 +81,14 @@ pub fn logins(
             hyper::Method::POST => {
                 let db_pool = db_pool.clone();
                 let cpupool = cpupool.clone();
+                let attempts = server_state.login_attempts.clone();
+                let dummy_passhash = server_state.dummy_passhash.clone();
+                let hash_params = server_state.settings.hash_params.clone();
+
+                let user_agent = req.headers().get(hyper::header::USER_AGENT)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.to_owned());
+                let ip = server_state.settings.client_ip_source.client_ip(req.headers(), remote_addr.ip()).to_string();
 
                 Box::new(req.into_body()
                          .concat2()
@@ -30,55 +98,141 @@ pub fn logins(
                                  .map_err(|err| crate::Error::Internal(Box::new(err)))
                          })
                          .and_then(move |body: LoginReqBody| {
-                             let LoginReqBody { email, password } = body;
+                             let LoginReqBody { email, password, totp_code, recovery_code } = body;
 
-                             db_pool.run(move |mut conn| {
-                                 conn.prepare("SELECT id, passhash FROM users WHERE email=$1")
-                                     .then(|res| tack_on(res, conn))
-                                     .and_then(move |(stmt, mut conn)| {
-                                         conn.query(&stmt, &[&email])
-                                             .into_future()
-                                             .map(|(res, _)| res)
-                                             .map_err(|(err, _)| err)
-                                             .then(|res| tack_on(res, conn))
-                                     })
-                             })
-                             .map_err(ErrorWrapper::from)
-                                 .map_err(|err| crate::Error::Internal(Box::new(err)))
-                                 .and_then(|row| {
-                                     match row {
-                                         Some(row) => Ok(row),
-                                         None => {
-                                             Err(crate::Error::Custom(hyper::Response::builder()
-                                                                      .status(hyper::StatusCode::BAD_REQUEST)
-                                                                      .body("No such user with that email address".into())))
-                                         }
+                             let attempt_keys = vec![
+                                 login_attempts::ip_key(&ip),
+                                 login_attempts::account_key(&email),
+                             ];
+
+                             // every attempt that gets past here is finished as a failure, released or cleared
+                             login_attempts::begin(&*attempts, &attempt_keys)
+                                 .and_then({
+                                     let db_pool = db_pool.clone();
+                                     move |_| {
+                                         db_pool.run(move |mut conn| {
+                                             conn.prepare("SELECT id, passhash, CASE WHEN totp_enabled THEN totp_secret END FROM users WHERE email=$1")
+                                                 .then(|res| tack_on(res, conn))
+                                                 .and_then(move |(stmt, mut conn)| {
+                                                     conn.query(&stmt, &[&email])
+                                                         .into_future()
+                                                         .map(|(res, _)| res)
+                                                         .map_err(|(err, _)| err)
+                                                         .then(|res| tack_on(res, conn))
+                                                 })
+                                         })
+                                         .map_err(ErrorWrapper::from)
+                                             .map_err(|err| crate::Error::Internal(Box::new(err)))
                                      }
                                  })
-                             .and_then(move |row| {
-                                 let user_id: i32 = row.get(0);
-                                 let passhash: String = row.get(1);
+                                 .and_then({
+                                     let db_pool = db_pool.clone();
+                                     let attempts = attempts.clone();
+                                     let attempt_keys = attempt_keys.clone();
+                                     move |row| {
+                                         // unknown users are checked against a dummy hash so they take as long as real ones
+                                         let (user_id, passhash, totp_secret) = match row {
+                                             Some(row) => (Some(row.get::<_, i32>(0)), row.get::<_, String>(1), row.get::<_, Option<Vec<u8>>>(2)),
+                                             None => (None, (*dummy_passhash).clone(), None),
+                                         };
 
-                                 cpupool.spawn_fn(move || {
-                                     bcrypt::verify(password, &passhash)
-                                 })
-                                 .map_err(|err| crate::Error::Internal(Box::new(err)))
-                                 .and_then(|correct| {
-                                     if !correct {
-                                         Err(crate::Error::Custom(hyper::Response::builder()
-                                                                  .status(hyper::StatusCode::UNAUTHORIZED)
-                                                                  .body("Incorrect password".into())))
-                                     } else {
-                                         Ok(())
+                                         cpupool.spawn_fn(move || {
+                                             crate::passwords::verify(&password, &passhash)
+                                                 .map(|correct| (correct, password, passhash))
+                                         })
+                                         .and_then({
+                                             let cpupool = cpupool.clone();
+                                             let db_pool = db_pool.clone();
+                                             let attempts = attempts.clone();
+                                             let attempt_keys = attempt_keys.clone();
+                                             move |(correct, password, passhash)| {
+                                                 match user_id {
+                                                     Some(user_id) if correct => {
+                                                         if crate::passwords::needs_rehash(&passhash, &hash_params) {
+                                                             tokio::spawn(rehash(&cpupool, &db_pool, user_id, password, passhash, hash_params));
+                                                         }
+
+                                                         futures::future::Either::A(futures::future::ok(user_id))
+                                                     }
+                                                     _ => futures::future::Either::B(attempt_failed(&*attempts, &attempt_keys, crate::Error::Custom(hyper::Response::builder()
+                                                                                                                                                    .status(hyper::StatusCode::UNAUTHORIZED)
+                                                                                                                                                    .body("Incorrect email or password".into())))),
+                                                 }
+                                             }
+                                         })
+                                         .and_then({
+                                             let db_pool = db_pool.clone();
+                                             let attempts = attempts.clone();
+                                             let attempt_keys = attempt_keys.clone();
+                                             move |user_id| -> Box<dyn Future<Item = i32, Error = crate::Error> + Send> {
+                                                 let totp_secret = match totp_secret {
+                                                     Some(totp_secret) => totp_secret,
+                                                     None => return Box::new(futures::future::ok(user_id)),
+                                                 };
+
+                                                 if let Some(code) = totp_code {
+                                                     let incorrect_code = || crate::Error::Custom(hyper::Response::builder()
+                                                                                                  .status(hyper::StatusCode::UNAUTHORIZED)
+                                                                                                  .body("Incorrect two-factor code".into()));
+
+                                                     match crate::totp::verify(&totp_secret, &code) {
+                                                         Some(step) => {
+                                                             Box::new(crate::totp::record_step(&db_pool, user_id, step)
+                                                                      .and_then(move |fresh| {
+                                                                          if fresh {
+                                                                              futures::future::Either::A(futures::future::ok(user_id))
+                                                                          } else {
+                                                                              futures::future::Either::B(attempt_failed(&*attempts, &attempt_keys, incorrect_code()))
+                                                                          }
+                                                                      }))
+                                                         }
+                                                         None => attempt_failed(&*attempts, &attempt_keys, incorrect_code()),
+                                                     }
+                                                 } else if let Some(code) = recovery_code {
+                                                     let code_hash = crate::totp::hash_recovery_code(&code);
+
+                                                     Box::new(db_pool.run(move |mut conn| {
+                                                         conn.prepare("DELETE FROM totp_recovery_codes WHERE user_id=$1 AND code_hash=$2")
+                                                             .then(|res| tack_on(res, conn))
+                                                             .and_then(move |(stmt, mut conn)| {
+                                                                 conn.execute(&stmt, &[&user_id, &code_hash])
+                                                                     .then(|res| tack_on(res, conn))
+                                                             })
+                                                     })
+                                                              .map_err(ErrorWrapper::from)
+                                                              .map_err(crate::Error::internal)
+                                                              .and_then(move |count| {
+                                                                  if count > 0 {
+                                                                      futures::future::Either::A(futures::future::ok(user_id))
+                                                                  } else {
+                                                                      futures::future::Either::B(attempt_failed(&*attempts, &attempt_keys, crate::Error::Custom(hyper::Response::builder()
+                                                                                                                                                             .status(hyper::StatusCode::UNAUTHORIZED)
+                                                                                                                                                             .body("Incorrect recovery code".into()))))
+                                                                  }
+                                                              }))
+                                                 } else {
+                                                     // the password was right, this just asks for the second step
+                                                     attempt_released(&*attempts, &attempt_keys, crate::Error::Custom(hyper::Response::builder()
+                                                                                                                  .status(hyper::StatusCode::UNAUTHORIZED)
+                                                                                                                  .body("Two-factor code required".into())))
+                                                 }
+                                             }
+                                         })
                                      }
                                  })
-                                 .and_then(move |_| {
+                                 .and_then(move |user_id| {
+                                     // the per-IP counter only takes back this attempt, so one valid account can't be used to reset it
+                                     attempts.release(&attempt_keys[..1])
+                                         .join(attempts.clear(&attempt_keys[1..]))
+                                         .map(move |_| user_id)
+                                 })
+                                 .and_then(move |user_id| {
                                      let token = uuid::Uuid::new_v4();
                                      db_pool.run(move |mut conn| {
-                                         conn.prepare("INSERT INTO logins (token, user_id, created) VALUES ($1, $2, localtimestamp)")
+                                         conn.prepare("INSERT INTO logins (token, user_id, created, user_agent, ip) VALUES ($1, $2, localtimestamp, $3, $4)")
                                              .then(|res| tack_on(res, conn))
                                              .and_then(move |(stmt, mut conn)| {
-                                                 conn.execute(&stmt, &[&token, &user_id])
+                                                 conn.execute(&stmt, &[&token, &user_id, &user_agent, &ip])
                                                      .map(move |_| token)
                                                      .then(|res| tack_on(res, conn))
                                              })
@@ -86,7 +240,6 @@ pub fn logins(
                                      .map_err(ErrorWrapper::from)
                                          .map_err(|err| crate::Error::Internal(Box::new(err)))
                                  })
-                             })
                              .and_then(|token| {
                                  hyper::Response::builder()
                                      .body(token.to_string().into())
@@ -96,6 +249,58 @@ pub fn logins(
             }
             _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
         }
+    } else if let Some((segment, path)) = crate::consume_path_segment(path) {
+        if !path.is_empty() {
+            return Box::new(futures::future::err(crate::Error::NotFound));
+        }
+
+        match *req.method() {
+            hyper::Method::DELETE => {
+                let db_pool = db_pool.clone();
+
+                let token = if segment == "~current" {
+                    match crate::get_auth_token(&req) {
+                        Some(Ok(token)) => Ok(token),
+                        _ => Err(crate::Error::Custom(hyper::Response::builder()
+                                                      .status(hyper::StatusCode::UNAUTHORIZED)
+                                                      .body("Login is required for '~current' paths".into()))),
+                    }
+                } else {
+                    segment.parse::<uuid::Uuid>()
+                        .map_err(|_| crate::Error::Custom(hyper::Response::builder()
+                                                          .status(hyper::StatusCode::BAD_REQUEST)
+                                                          .body("Invalid login token".into())))
+                };
+
+                Box::new(token
+                         .into_future()
+                         .and_then(move |token| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("DELETE FROM logins WHERE token=$1")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.execute(&stmt, &[&token])
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|count| {
+                             if count > 0 {
+                                 hyper::Response::builder()
+                                     .status(hyper::StatusCode::NO_CONTENT)
+                                     .body(hyper::Body::empty())
+                                     .map_err(crate::Error::internal)
+                             } else {
+                                 Err(crate::Error::Custom(hyper::Response::builder()
+                                                          .status(hyper::StatusCode::NOT_FOUND)
+                                                          .body("No such login".into())))
+                             }
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
     } else {
         Box::new(futures::future::err(crate::Error::NotFound))
     }
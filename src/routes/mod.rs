@@ -1,10 +1,16 @@
+mod email_verifications;
 mod logins;
+mod password_resets;
+mod redirect_rules;
+mod redirect_schedule;
 mod redirects;
 mod settings;
 mod subscription_tiers;
 mod users;
 
+pub use self::email_verifications::email_verifications;
 pub use self::logins::logins;
+pub use self::password_resets::password_resets;
 pub use self::redirects::redirects_path as redirects;
 pub use self::settings::settings;
 pub use self::subscription_tiers::subscription_tiers;
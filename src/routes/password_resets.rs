@@ -0,0 +1,181 @@
+use futures::{Future, IntoFuture, Stream};
+use serde_derive::Deserialize;
+use std::sync::Arc;
+
+use crate::{tack_on, DbPool, ErrorWrapper, ServerState};
+
+#[derive(Deserialize)]
+struct PasswordResetCreateBody {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordResetCompleteBody {
+    password: String,
+}
+
+pub fn password_resets(
+    cpupool: &Arc<futures_cpupool::CpuPool>,
+    db_pool: &DbPool,
+    server_state: &ServerState,
+    req: hyper::Request<hyper::Body>,
+    path: &str,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    if path.is_empty() {
+        match *req.method() {
+            hyper::Method::POST => {
+                let db_pool = db_pool.clone();
+                let mailer = server_state.mailer.clone();
+
+                // responds the same way whether or not the address belongs to a user
+                Box::new(server_state.settings.frontend_host.clone()
+                         .ok_or_else(|| crate::Error::internal(ErrorWrapper::Text("Missing frontend host".to_owned())))
+                         .into_future()
+                         .join(req.into_body()
+                               .concat2()
+                               .map_err(crate::Error::internal)
+                               .and_then(|body| {
+                                   serde_json::from_slice(&body)
+                                       .map_err(crate::Error::internal)
+                               }))
+                         .and_then(move |(frontend_host, body): (_, PasswordResetCreateBody)| {
+                             let token = uuid::Uuid::new_v4();
+                             let email = body.email;
+                             let to = email.clone();
+
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("INSERT INTO password_resets (token, user_id, created) SELECT $1, id, localtimestamp FROM users WHERE email=$2")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.execute(&stmt, &[&token, &email])
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .map(move |count| {
+                                     // sent in the background, so the response takes as long either way
+                                     if count > 0 {
+                                         tokio::spawn(mailer.send(crate::mail::Message {
+                                             to,
+                                             subject: "Reset your password".to_owned(),
+                                             body: format!("Someone requested a password reset for your account. To choose a new password, open this link within the next hour:\n\n{}/resetPassword?token={}\n\nIf this wasn't you, you can ignore this email.", frontend_host, token),
+                                         })
+                                                      .map_err(|err| {
+                                                          if let crate::Error::Internal(err) = err {
+                                                              eprintln!("Failed to send password reset email: {:?}", err);
+                                                          }
+                                                      }));
+                                     }
+                                 })
+                         })
+                         .and_then(|_| {
+                             hyper::Response::builder()
+                                 .status(hyper::StatusCode::NO_CONTENT)
+                                 .body(hyper::Body::empty())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else if let Some((segment, path)) = crate::consume_path_segment(path) {
+        if !path.is_empty() {
+            return Box::new(futures::future::err(crate::Error::NotFound));
+        }
+
+        let token = match segment.parse::<uuid::Uuid>() {
+            Ok(token) => token,
+            Err(_err) => {
+                return Box::new(futures::future::err(crate::Error::Custom(
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::BAD_REQUEST)
+                        .body("Invalid password reset token".into()),
+                )));
+            }
+        };
+
+        match *req.method() {
+            hyper::Method::POST => {
+                let cpupool = cpupool.clone();
+                let db_pool = db_pool.clone();
+                let hash_params = server_state.settings.hash_params.clone();
+
+                Box::new(req.into_body()
+                         .concat2()
+                         .map_err(crate::Error::internal)
+                         .and_then(|body| {
+                             serde_json::from_slice(&body)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then({
+                             let db_pool = db_pool.clone();
+                             move |body: PasswordResetCompleteBody| {
+                                 // checked before hashing, so an invalid token doesn't cost a hash
+                                 db_pool.run(move |mut conn| {
+                                     conn.prepare("SELECT 1 FROM password_resets WHERE token=$1 AND created > localtimestamp - INTERVAL '1 hour'")
+                                         .then(|res| tack_on(res, conn))
+                                         .and_then(move |(stmt, mut conn)| {
+                                             conn.query(&stmt, &[&token])
+                                                 .into_future()
+                                                 .map(|(res, _)| res)
+                                                 .map_err(|(err, _)| err)
+                                                 .then(|res| tack_on(res, conn))
+                                         })
+                                 })
+                                 .map_err(ErrorWrapper::from)
+                                     .map_err(crate::Error::internal)
+                                     .and_then(move |row| {
+                                         match row {
+                                             Some(_) => Ok(body.password),
+                                             None => Err(invalid_token()),
+                                         }
+                                     })
+                             }
+                         })
+                         .and_then(move |password| {
+                             cpupool.spawn_fn(move || {
+                                 crate::passwords::hash(&password, &hash_params)
+                             })
+                         })
+                         .and_then(move |passhash| {
+                             // the token is consumed even if it turns out to have expired
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("WITH reset AS (DELETE FROM password_resets WHERE token=$1 RETURNING user_id, created), updated AS (UPDATE users SET passhash=$2 FROM reset WHERE users.id=reset.user_id AND reset.created > localtimestamp - INTERVAL '1 hour' RETURNING users.id), revoked AS (DELETE FROM logins WHERE user_id IN (SELECT id FROM updated)) SELECT id FROM updated")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&token, &passhash])
+                                             .into_future()
+                                             .map(|(res, _)| res)
+                                             .map_err(|(err, _)| err)
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|row| {
+                             match row {
+                                 Some(_) => {
+                                     hyper::Response::builder()
+                                         .status(hyper::StatusCode::NO_CONTENT)
+                                         .body(hyper::Body::empty())
+                                         .map_err(crate::Error::internal)
+                                 }
+                                 None => Err(invalid_token()),
+                             }
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else {
+        Box::new(futures::future::err(crate::Error::NotFound))
+    }
+}
+
+fn invalid_token() -> crate::Error {
+    crate::Error::Custom(
+        hyper::Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body("Invalid or expired password reset token".into()),
+    )
+}
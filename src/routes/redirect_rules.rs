@@ -0,0 +1,389 @@
+use futures::{Future, Stream};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::redirects::ensure_owner;
+use crate::api_keys::Scope;
+use crate::path_mode::PathMode;
+use crate::{tack_on, DbPool, ErrorWrapper, ServerState};
+
+/// How a rule's pattern is compared against the request path.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MatchType {
+    #[serde(rename = "exact")]
+    Exact,
+    #[serde(rename = "prefix")]
+    Prefix,
+    /// `*` matches within a single path segment, `**` matches across segments.
+    #[serde(rename = "glob")]
+    Glob,
+}
+
+impl MatchType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MatchType::Exact => "exact",
+            MatchType::Prefix => "prefix",
+            MatchType::Glob => "glob",
+        }
+    }
+
+    /// Reads the `match_type` column, which is constrained to the values from `as_str`.
+    pub fn from_column(value: &str) -> Self {
+        match value {
+            "prefix" => MatchType::Prefix,
+            "glob" => MatchType::Glob,
+            _ => MatchType::Exact,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RuleCreateBody {
+    match_type: MatchType,
+    pattern: String,
+    destination: String,
+    #[serde(default = "super::users::default_status_code")]
+    status_code: i32,
+    position: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct RulePatchBody {
+    match_type: Option<MatchType>,
+    pattern: Option<String>,
+    destination: Option<String>,
+    status_code: Option<i32>,
+    position: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct RuleInfo {
+    pub id: i32,
+    pub position: i32,
+    pub match_type: MatchType,
+    pub pattern: String,
+    pub destination: String,
+    pub status_code: i32,
+}
+
+impl RuleInfo {
+    /// Reads a row starting with `id, position, match_type, pattern, destination, status_code`.
+    pub fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            id: row.get(0),
+            position: row.get(1),
+            match_type: MatchType::from_column(row.get(2)),
+            pattern: row.get(3),
+            destination: row.get(4),
+            status_code: row.get(5),
+        }
+    }
+}
+
+fn no_such_rule() -> crate::Error {
+    crate::Error::Custom(
+        hyper::Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body("No such rule".into()),
+    )
+}
+
+fn query_rule(
+    db_pool: &DbPool,
+    redirect_id: i32,
+    rule_id: i32,
+) -> impl Future<Item = RuleInfo, Error = crate::Error> + Send {
+    db_pool.run(move |mut conn| {
+        conn.prepare("SELECT id, position, match_type, pattern, destination, status_code FROM redirect_rules WHERE id=$1 AND redirect_id=$2")
+            .then(|res| tack_on(res, conn))
+            .and_then(move |(stmt, mut conn)| {
+                conn.query(&stmt, &[&rule_id, &redirect_id])
+                    .into_future()
+                    .map(|(res, _)| res)
+                    .map_err(|(err, _)| err)
+                    .then(|res| tack_on(res, conn))
+            })
+    })
+    .map_err(ErrorWrapper::from)
+        .map_err(crate::Error::internal)
+        .and_then(|row| {
+            row.map(|row| RuleInfo::from_row(&row))
+                .ok_or_else(no_such_rule)
+        })
+}
+
+/// Rules are checked in ascending `position` order, and the first match decides where a request
+/// goes. Requests matching no rule use the redirect's own destination. Rule destinations are used
+/// as-is, regardless of the redirect's path mode.
+pub fn rules_path(
+    db_pool: &DbPool,
+    server_state: &ServerState,
+    req: hyper::Request<hyper::Body>,
+    redirect_id: i32,
+    path: &str,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    if path.is_empty() {
+        match *req.method() {
+            hyper::Method::GET => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_owner(&db_pool, server_state, &req, redirect_id, Scope::RedirectsRead)
+                         .and_then(move |_| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("SELECT id, position, match_type, pattern, destination, status_code FROM redirect_rules WHERE redirect_id=$1 ORDER BY position, id")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&redirect_id])
+                                             .collect()
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .map(|rows| {
+                                     rows.iter().map(RuleInfo::from_row).collect::<Vec<_>>()
+                                 })
+                         })
+                         .and_then(|result| {
+                             serde_json::to_vec(&result)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            hyper::Method::POST => {
+                let db_pool = db_pool.clone();
+                let settings = server_state.settings.clone();
+
+                Box::new(ensure_owner(&db_pool, server_state, &req, redirect_id, Scope::RedirectsWrite)
+                         .and_then(move |redirect| {
+                             req.into_body()
+                                 .concat2()
+                                 .map_err(crate::Error::internal)
+                                 .and_then(|body| {
+                                     serde_json::from_slice(&body)
+                                         .map_err(crate::Error::internal)
+                                 })
+                                 .and_then(move |body: RuleCreateBody| {
+                                     let pattern = crate::validation::normalize_rule_pattern(&body.pattern, body.match_type == MatchType::Glob)?;
+                                     let destination = crate::validation::normalize_destination(&body.destination, &redirect.host, PathMode::Discard, &settings)?;
+                                     crate::validation::validate_status_code(body.status_code)?;
+                                     Ok(RuleCreateBody { pattern, destination, ..body })
+                                 })
+                         })
+                         .and_then(move |body| {
+                             db_pool.run(move |mut conn| {
+                                 // without a position, the rule goes after all existing ones
+                                 conn.prepare("INSERT INTO redirect_rules (redirect_id, position, match_type, pattern, destination, status_code) SELECT $1, COALESCE($2, (SELECT COALESCE(MAX(position) + 1, 0) FROM redirect_rules WHERE redirect_id=$1)), $3, $4, $5, $6 WHERE (SELECT COUNT(*) FROM redirect_rules WHERE redirect_id=$1) < $7 RETURNING id, position")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&redirect_id, &body.position, &body.match_type.as_str(), &body.pattern, &body.destination, &body.status_code, &crate::validation::MAX_RULES_PER_REDIRECT])
+                                             .into_future()
+                                             .map(|(res, _)| res)
+                                             .map_err(|(err, _)| err)
+                                             .map(move |row| {
+                                                 row.map(|row| {
+                                                     RuleInfo {
+                                                         id: row.get(0),
+                                                         position: row.get(1),
+                                                         match_type: body.match_type,
+                                                         pattern: body.pattern,
+                                                         destination: body.destination,
+                                                         status_code: body.status_code,
+                                                     }
+                                                 })
+                                             })
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .and_then(|info| {
+                                     // nothing is inserted once the redirect has reached the limit
+                                     info.ok_or_else(|| crate::validation::rule_limit_reached().into())
+                                 })
+                         })
+                         .and_then(|info| {
+                             serde_json::to_vec(&info)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .status(hyper::StatusCode::CREATED)
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else if let Some((segment, path)) = crate::consume_path_segment(path) {
+        if !path.is_empty() {
+            return Box::new(futures::future::err(crate::Error::NotFound));
+        }
+
+        let rule_id = match segment.parse::<i32>() {
+            Ok(rule_id) => rule_id,
+            Err(_err) => {
+                return Box::new(futures::future::err(crate::Error::Custom(
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::BAD_REQUEST)
+                        .body("Invalid rule ID".into()),
+                )));
+            }
+        };
+
+        match *req.method() {
+            hyper::Method::GET => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_owner(&db_pool, server_state, &req, redirect_id, Scope::RedirectsRead)
+                         .and_then(move |_| query_rule(&db_pool, redirect_id, rule_id))
+                         .and_then(|info| {
+                             serde_json::to_vec(&info)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            hyper::Method::PATCH => {
+                let db_pool = db_pool.clone();
+                let settings = server_state.settings.clone();
+
+                Box::new(ensure_owner(&db_pool, server_state, &req, redirect_id, Scope::RedirectsWrite)
+                         .and_then({
+                             let db_pool = db_pool.clone();
+                             move |redirect| {
+                                 query_rule(&db_pool, redirect_id, rule_id)
+                                     .map(move |rule| (redirect, rule))
+                             }
+                         })
+                         .and_then(move |(redirect, rule)| {
+                             req.into_body()
+                                 .concat2()
+                                 .map_err(crate::Error::internal)
+                                 .and_then(|body| {
+                                     serde_json::from_slice(&body)
+                                         .map_err(crate::Error::internal)
+                                 })
+                                 .and_then(move |body: RulePatchBody| {
+                                     // a new match type could make the existing pattern invalid too
+                                     let match_type = body.match_type.unwrap_or(rule.match_type);
+                                     let mut new_pattern = None;
+                                     if body.match_type.is_some() || body.pattern.is_some() {
+                                         let pattern = body.pattern.as_ref().unwrap_or(&rule.pattern);
+                                         let pattern = crate::validation::normalize_rule_pattern(pattern, match_type == MatchType::Glob)?;
+                                         if body.pattern.is_some() {
+                                             new_pattern = Some(pattern);
+                                         }
+                                     }
+                                     let destination = match body.destination {
+                                         Some(ref destination) => Some(crate::validation::normalize_destination(destination, &redirect.host, PathMode::Discard, &settings)?),
+                                         None => None,
+                                     };
+                                     if let Some(status_code) = body.status_code {
+                                         crate::validation::validate_status_code(status_code)?;
+                                     }
+
+                                     let mut changes: HashMap<&str, Box<dyn tokio_postgres::types::ToSql + Send + Sync>> = HashMap::new();
+                                     if let Some(match_type) = body.match_type {
+                                         changes.insert("match_type", Box::new(match_type.as_str()));
+                                     }
+                                     if let Some(pattern) = new_pattern {
+                                         changes.insert("pattern", Box::new(pattern));
+                                     }
+                                     if let Some(destination) = destination {
+                                         changes.insert("destination", Box::new(destination));
+                                     }
+                                     if let Some(status_code) = body.status_code {
+                                         changes.insert("status_code", Box::new(status_code));
+                                     }
+                                     if let Some(position) = body.position {
+                                         changes.insert("position", Box::new(position));
+                                     }
+
+                                     Ok(changes)
+                                 })
+                         })
+                         .and_then(move |changes| {
+                             if changes.is_empty() {
+                                 futures::future::Either::A(futures::future::ok(()))
+                             } else {
+                                 let mut values: Vec<Box<dyn tokio_postgres::types::ToSql + Send + Sync>> = vec![Box::new(rule_id), Box::new(redirect_id)];
+
+                                 let assignments: Vec<_> = changes.into_iter().map(|(key, value)| {
+                                     values.push(value);
+                                     format!("\"{}\" = ${}", key, values.len())
+                                 }).collect();
+
+                                 let sql = format!("UPDATE redirect_rules SET {} WHERE id=$1 AND redirect_id=$2", assignments.join(", "));
+
+                                 futures::future::Either::B(db_pool.run(move |mut conn| {
+                                     conn.prepare(&sql)
+                                         .then(|res| tack_on(res, conn))
+                                         .and_then(move |(stmt, mut conn)| {
+                                             let values: Vec<_> = values.iter().map(|x| x.as_ref() as &dyn tokio_postgres::types::ToSql).collect();
+                                             conn.execute(&stmt, &values[..])
+                                                 .then(|res| tack_on(res, conn))
+                                         })
+                                 })
+                                                            .map_err(ErrorWrapper::from)
+                                                            .map_err(crate::Error::internal)
+                                                            .and_then(|count| {
+                                                                // deleted since it was looked up
+                                                                if count > 0 {
+                                                                    Ok(())
+                                                                } else {
+                                                                    Err(no_such_rule())
+                                                                }
+                                                            }))
+                             }
+                         })
+                         .and_then(|_| {
+                             hyper::Response::builder()
+                                 .body(hyper::Body::empty())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            hyper::Method::DELETE => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_owner(&db_pool, server_state, &req, redirect_id, Scope::RedirectsWrite)
+                         .and_then(move |_| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("DELETE FROM redirect_rules WHERE id=$1 AND redirect_id=$2")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.execute(&stmt, &[&rule_id, &redirect_id])
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|count| {
+                             if count > 0 {
+                                 hyper::Response::builder()
+                                     .status(hyper::StatusCode::NO_CONTENT)
+                                     .body(hyper::Body::empty())
+                                     .map_err(crate::Error::internal)
+                             } else {
+                                 Err(no_such_rule())
+                             }
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else {
+        Box::new(futures::future::err(crate::Error::NotFound))
+    }
+}
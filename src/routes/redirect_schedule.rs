@@ -0,0 +1,292 @@
+use futures::{Future, Stream};
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use super::redirects::ensure_owner;
+use crate::api_keys::Scope;
+use crate::path_mode::PathMode;
+use crate::validation::ValidationError;
+use crate::{tack_on, DbPool, ErrorWrapper, ServerState, Settings};
+
+#[derive(Deserialize)]
+struct ScheduledChangeCreateBody {
+    at: chrono::DateTime<chrono::Utc>,
+    destination: String,
+}
+
+#[derive(Serialize)]
+pub struct ScheduledChangeInfo {
+    pub id: i32,
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub destination: String,
+}
+
+#[derive(Serialize)]
+struct HistoryEntryInfo {
+    previous_destination: String,
+    destination: String,
+    scheduled_at: chrono::DateTime<chrono::Utc>,
+    applied_at: chrono::DateTime<chrono::Utc>,
+}
+
+fn no_such_change() -> crate::Error {
+    crate::Error::Custom(
+        hyper::Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body("No such scheduled change".into()),
+    )
+}
+
+/// Fails if any pending change for the redirect would no longer be a valid destination with the
+/// given host and path mode.
+pub fn check_pending_changes(
+    db_pool: &DbPool,
+    redirect_id: i32,
+    host: String,
+    path_mode: PathMode,
+    settings: Arc<Settings>,
+) -> impl Future<Item = (), Error = crate::Error> + Send {
+    db_pool.run(move |mut conn| {
+        conn.prepare("SELECT id, destination FROM scheduled_changes WHERE redirect_id=$1")
+            .then(|res| tack_on(res, conn))
+            .and_then(move |(stmt, mut conn)| {
+                conn.query(&stmt, &[&redirect_id])
+                    .collect()
+                    .then(|res| tack_on(res, conn))
+            })
+    })
+    .map_err(ErrorWrapper::from)
+        .map_err(crate::Error::internal)
+        .and_then(move |rows| {
+            for row in rows {
+                let change_id: i32 = row.get(0);
+                let destination: String = row.get(1);
+
+                if let Err(err) = crate::validation::normalize_destination(&destination, &host, path_mode, &settings) {
+                    return Err(ValidationError {
+                        field: "schedule",
+                        rule: err.rule,
+                        message: format!("Scheduled change {} must be cancelled first: {}", change_id, err.message),
+                    }.into());
+                }
+            }
+
+            Ok(())
+        })
+}
+
+/// Pending changes are applied by `crate::schedule` once their time comes, after which they no
+/// longer appear here.
+pub fn schedule_path(
+    db_pool: &DbPool,
+    server_state: &ServerState,
+    req: hyper::Request<hyper::Body>,
+    redirect_id: i32,
+    path: &str,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    if path.is_empty() {
+        match *req.method() {
+            hyper::Method::GET => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_owner(&db_pool, server_state, &req, redirect_id, Scope::RedirectsRead)
+                         .and_then(move |_| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("SELECT id, at, destination FROM scheduled_changes WHERE redirect_id=$1 ORDER BY at, id")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&redirect_id])
+                                             .collect()
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .map(|rows| {
+                                     rows.into_iter().map(|row| {
+                                         ScheduledChangeInfo {
+                                             id: row.get(0),
+                                             at: row.get(1),
+                                             destination: row.get(2),
+                                         }
+                                     }).collect::<Vec<_>>()
+                                 })
+                         })
+                         .and_then(|result| {
+                             serde_json::to_vec(&result)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            hyper::Method::POST => {
+                let db_pool = db_pool.clone();
+                let settings = server_state.settings.clone();
+
+                Box::new(ensure_owner(&db_pool, server_state, &req, redirect_id, Scope::RedirectsWrite)
+                         .and_then(move |redirect| {
+                             req.into_body()
+                                 .concat2()
+                                 .map_err(crate::Error::internal)
+                                 .and_then(|body| {
+                                     serde_json::from_slice(&body)
+                                         .map_err(crate::Error::internal)
+                                 })
+                                 .and_then(move |body: ScheduledChangeCreateBody| {
+                                     crate::validation::validate_scheduled_time(body.at)?;
+                                     let destination = crate::validation::normalize_destination(&body.destination, &redirect.host, redirect.path_mode, &settings)?;
+                                     Ok(ScheduledChangeCreateBody { destination, ..body })
+                                 })
+                         })
+                         .and_then(move |body| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("INSERT INTO scheduled_changes (redirect_id, at, destination) SELECT $1, $2, $3 WHERE (SELECT COUNT(*) FROM scheduled_changes WHERE redirect_id=$1) < $4 RETURNING id")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&redirect_id, &body.at, &body.destination, &crate::validation::MAX_SCHEDULED_CHANGES_PER_REDIRECT])
+                                             .into_future()
+                                             .map(|(res, _)| res)
+                                             .map_err(|(err, _)| err)
+                                             .map(move |row| {
+                                                 row.map(|row| {
+                                                     ScheduledChangeInfo {
+                                                         id: row.get(0),
+                                                         at: body.at,
+                                                         destination: body.destination,
+                                                     }
+                                                 })
+                                             })
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .and_then(|info| {
+                                     // nothing is inserted once the redirect has reached the limit
+                                     info.ok_or_else(|| crate::validation::scheduled_change_limit_reached().into())
+                                 })
+                         })
+                         .and_then(|info| {
+                             serde_json::to_vec(&info)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .status(hyper::StatusCode::CREATED)
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else if let Some((segment, path)) = crate::consume_path_segment(path) {
+        if !path.is_empty() {
+            return Box::new(futures::future::err(crate::Error::NotFound));
+        }
+
+        let change_id = match segment.parse::<i32>() {
+            Ok(change_id) => change_id,
+            Err(_err) => {
+                return Box::new(futures::future::err(crate::Error::Custom(
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::BAD_REQUEST)
+                        .body("Invalid scheduled change ID".into()),
+                )));
+            }
+        };
+
+        match *req.method() {
+            hyper::Method::DELETE => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_owner(&db_pool, server_state, &req, redirect_id, Scope::RedirectsWrite)
+                         .and_then(move |_| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("DELETE FROM scheduled_changes WHERE id=$1 AND redirect_id=$2")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.execute(&stmt, &[&change_id, &redirect_id])
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|count| {
+                             // changes that were already applied are gone too
+                             if count > 0 {
+                                 hyper::Response::builder()
+                                     .status(hyper::StatusCode::NO_CONTENT)
+                                     .body(hyper::Body::empty())
+                                     .map_err(crate::Error::internal)
+                             } else {
+                                 Err(no_such_change())
+                             }
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else {
+        Box::new(futures::future::err(crate::Error::NotFound))
+    }
+}
+
+/// Scheduled changes that have been applied, most recent first.
+pub fn history_path(
+    db_pool: &DbPool,
+    server_state: &ServerState,
+    req: hyper::Request<hyper::Body>,
+    redirect_id: i32,
+    path: &str,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    if !path.is_empty() {
+        return Box::new(futures::future::err(crate::Error::NotFound));
+    }
+
+    match *req.method() {
+        hyper::Method::GET => {
+            let db_pool = db_pool.clone();
+
+            Box::new(ensure_owner(&db_pool, server_state, &req, redirect_id, Scope::RedirectsRead)
+                     .and_then(move |_| {
+                         db_pool.run(move |mut conn| {
+                             conn.prepare("SELECT previous_destination, destination, scheduled_at, applied_at FROM redirect_history WHERE redirect_id=$1 ORDER BY applied_at DESC, scheduled_at DESC")
+                                 .then(|res| tack_on(res, conn))
+                                 .and_then(move |(stmt, mut conn)| {
+                                     conn.query(&stmt, &[&redirect_id])
+                                         .collect()
+                                         .then(|res| tack_on(res, conn))
+                                 })
+                         })
+                         .map_err(ErrorWrapper::from)
+                             .map_err(crate::Error::internal)
+                             .map(|rows| {
+                                 rows.into_iter().map(|row| {
+                                     HistoryEntryInfo {
+                                         previous_destination: row.get(0),
+                                         destination: row.get(1),
+                                         scheduled_at: row.get(2),
+                                         applied_at: row.get(3),
+                                     }
+                                 }).collect::<Vec<_>>()
+                             })
+                     })
+                     .and_then(|result| {
+                         serde_json::to_vec(&result)
+                             .map_err(crate::Error::internal)
+                     })
+                     .and_then(|body| {
+                         hyper::Response::builder()
+                             .header(hyper::header::CONTENT_TYPE, "application/json")
+                             .body(body.into())
+                             .map_err(crate::Error::internal)
+                     }))
+        }
+        _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+    }
+}
@@ -2,11 +2,12 @@ use futures::{Future, Stream};
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-use crate::routes::users::RedirectInfo;
-use crate::{tack_on, DbPool, ErrorWrapper};
+use crate::path_mode::PathMode;
+use crate::routes::users::{HoldingResponse, RedirectInfo};
+use crate::{tack_on, DbPool, ErrorWrapper, ServerState, UserID};
 
 #[derive(Serialize)]
-enum RedirectTLSState {
+pub enum RedirectTLSState {
     #[serde(rename = "ready")]
     Ready,
     #[serde(rename = "error")]
@@ -15,26 +16,151 @@ enum RedirectTLSState {
     Pending,
 }
 
+impl RedirectTLSState {
+    pub fn new(has_cert: bool, acme_failed: bool) -> Self {
+        if has_cert {
+            RedirectTLSState::Ready
+        } else if acme_failed {
+            RedirectTLSState::Error
+        } else {
+            RedirectTLSState::Pending
+        }
+    }
+}
+
+/// The ACME challenge needed to issue a certificate for the host.
+#[derive(Serialize)]
+pub enum RedirectTLSChallenge {
+    #[serde(rename = "http-01")]
+    Http01,
+    /// Wildcard certificates can only be issued through DNS.
+    #[serde(rename = "dns-01")]
+    Dns01,
+}
+
 #[derive(Serialize)]
-struct RedirectTLSInfo {
-    state: RedirectTLSState,
+pub struct RedirectTLSInfo {
+    pub state: RedirectTLSState,
+    pub wildcard: bool,
+    pub challenge: RedirectTLSChallenge,
+}
+
+impl RedirectTLSInfo {
+    pub fn new(host: &str, has_cert: bool, acme_failed: bool) -> Self {
+        let wildcard = crate::validation::wildcard_base(host).is_some();
+
+        Self {
+            state: RedirectTLSState::new(has_cert, acme_failed),
+            wildcard,
+            challenge: if wildcard {
+                RedirectTLSChallenge::Dns01
+            } else {
+                RedirectTLSChallenge::Http01
+            },
+        }
+    }
 }
 
 #[derive(Serialize)]
-struct RedirectInfoExpanded {
+pub struct RedirectInfoExpanded {
     #[serde(flatten)]
-    base: RedirectInfo,
-    tls: RedirectTLSInfo,
-    record_confirmed: bool,
+    pub base: RedirectInfo,
+    pub tls: RedirectTLSInfo,
+    pub record_confirmed: bool,
 }
 
 #[derive(Deserialize)]
 struct RedirectPatchBody {
+    host: Option<String>,
     destination: Option<String>,
+    status_code: Option<i32>,
+    path_mode: Option<PathMode>,
+    enabled: Option<bool>,
+    holding_response: Option<HoldingResponsePatch>,
+}
+
+#[derive(Deserialize)]
+struct HoldingResponsePatch {
+    status_code: Option<i32>,
+    message: Option<String>,
+}
+
+pub struct OwnedRedirect {
+    pub owner: UserID,
+    pub host: String,
+    pub destination: String,
+    pub path_mode: PathMode,
+}
+
+/// 409 Conflict naming the host, for when it already belongs to another redirect.
+pub fn host_conflict(host: &str) -> crate::Error {
+    let body = serde_json::to_vec(&serde_json::json!({
+        "host": host,
+        "message": "That host is already in use. If you control its DNS, you can claim it instead.",
+    }))
+    .expect("Failed to serialize conflict body");
+
+    crate::Error::Custom(
+        hyper::Response::builder()
+            .status(hyper::StatusCode::CONFLICT)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(body.into()),
+    )
+}
+
+/// Fails unless the request is authenticated as the owner of the redirect.
+pub fn ensure_owner(
+    db_pool: &DbPool,
+    server_state: &ServerState,
+    req: &hyper::Request<hyper::Body>,
+    id: i32,
+    scope: crate::api_keys::Scope,
+) -> impl Future<Item = OwnedRedirect, Error = crate::Error> + Send {
+    crate::rd_login(&db_pool, &server_state.settings, &req, Some(scope))
+        .join(db_pool.run(move |mut conn| {
+            conn.prepare("SELECT owner, host, destination, path_mode FROM redirects WHERE id=$1")
+                .then(|res| tack_on(res, conn))
+                .and_then(move |(stmt, mut conn)| {
+                    conn.query(&stmt, &[&id])
+                        .into_future()
+                        .map(|(res, _)| res)
+                        .map_err(|(err, _)| err)
+                        .then(|res| tack_on(res, conn))
+                })
+        })
+              .map_err(ErrorWrapper::from)
+              .map_err(crate::Error::internal)
+              .and_then(|row| {
+                  row.ok_or_else(|| crate::Error::Custom(hyper::Response::builder()
+                                                         .status(hyper::StatusCode::NOT_FOUND)
+                                                         .body("No such redirect".into())))
+              }))
+        .and_then(|(login_user, row)| {
+            let owner: i32 = row.get(0);
+            if let Some(login_user) = login_user {
+                if owner != login_user.to_raw() {
+                    Err(crate::Error::Custom(hyper::Response::builder()
+                                             .status(hyper::StatusCode::FORBIDDEN)
+                                             .body("That's not your redirect".into())))
+                } else {
+                    Ok(OwnedRedirect {
+                        owner: login_user,
+                        host: row.get(1),
+                        destination: row.get(2),
+                        path_mode: PathMode::from_column(row.get(3)),
+                    })
+                }
+            } else {
+                Err(crate::Error::Custom(hyper::Response::builder()
+                                         .status(hyper::StatusCode::UNAUTHORIZED)
+                                         .body("Login is required to access redirects".into())))
+            }
+        })
 }
 
 pub fn redirects_path(
     db_pool: &DbPool,
+    server_state: &ServerState,
     req: hyper::Request<hyper::Body>,
     path: &str,
 ) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
@@ -42,7 +168,7 @@ pub fn redirects_path(
         Box::new(futures::future::err(crate::Error::InvalidMethod))
     } else if let Some((segment, path)) = crate::consume_path_segment(path) {
         match segment.parse::<i32>() {
-            Ok(id) => redirect_path(db_pool, req, id, path),
+            Ok(id) => redirect_path(db_pool, server_state, req, id, path),
             Err(_err) => Box::new(futures::future::err(crate::Error::Custom(
                 hyper::Response::builder()
                     .status(hyper::StatusCode::BAD_REQUEST)
@@ -56,6 +182,7 @@ pub fn redirects_path(
 
 fn redirect_path(
     db_pool: &DbPool,
+    server_state: &ServerState,
     req: hyper::Request<hyper::Body>,
     id: i32,
     path: &str,
@@ -63,9 +190,9 @@ fn redirect_path(
     if path.is_empty() {
         match *req.method() {
             hyper::Method::GET => {
-                Box::new(crate::rd_login(&db_pool, &req)
+                Box::new(crate::rd_login(&db_pool, &server_state.settings, &req, Some(crate::api_keys::Scope::RedirectsRead))
                          .join(db_pool.run(move |mut conn| {
-                             conn.prepare("SELECT host, destination, owner, cache_visit_count_total, cache_visit_count_month, acme_failed, (tls_cert IS NOT NULL AND tls_privkey IS NOT NULL), record_confirmed FROM redirects WHERE id=$1")
+                             conn.prepare("SELECT host, destination, owner, cache_visit_count_total, cache_visit_count_month, acme_failed, (tls_cert IS NOT NULL AND tls_privkey IS NOT NULL), record_confirmed, status_code, path_mode, enabled, holding_status_code, holding_message FROM redirects WHERE id=$1")
                                  .then(|res| tack_on(res, conn))
                                  .and_then(move |(stmt, mut conn)| {
                                      conn.query(&stmt, &[&id])
@@ -107,16 +234,15 @@ fn redirect_path(
                                      destination: row.get(1),
                                      visits_total: row.get(3),
                                      visits_month: row.get(4),
-                                 },
-                                 tls: RedirectTLSInfo {
-                                     state: if row.get(6) {
-                                         RedirectTLSState::Ready
-                                     } else if row.get(5) {
-                                         RedirectTLSState::Error
-                                     } else {
-                                         RedirectTLSState::Pending
+                                     status_code: row.get(8),
+                                     path_mode: PathMode::from_column(row.get(9)),
+                                     enabled: row.get(10),
+                                     holding_response: HoldingResponse {
+                                         status_code: row.get(11),
+                                         message: row.get(12),
                                      },
                                  },
+                                 tls: RedirectTLSInfo::new(row.get(0), row.get(6), row.get(5)),
                                  record_confirmed: row.get(7),
                              };
 
@@ -132,42 +258,9 @@ fn redirect_path(
             },
             hyper::Method::PATCH => {
                 let db_pool = db_pool.clone();
-                Box::new(crate::rd_login(&db_pool, &req)
-                         .join(db_pool.run(move |mut conn| {
-                             conn.prepare("SELECT owner FROM redirects WHERE id=$1")
-                                 .then(|res| tack_on(res, conn))
-                                 .and_then(move |(stmt, mut conn)| {
-                                     conn.query(&stmt, &[&id])
-                                         .into_future()
-                                         .map(|(res, _)| res)
-                                         .map_err(|(err, _)| err)
-                                         .then(|res| tack_on(res, conn))
-                                 })
-                         })
-                               .map_err(ErrorWrapper::from)
-                               .map_err(crate::Error::internal)
-                               .and_then(|row| {
-                                   row.ok_or_else(|| crate::Error::Custom(hyper::Response::builder()
-                                                                          .status(hyper::StatusCode::NOT_FOUND)
-                                                                          .body("No such redirect".into())))
-                               }))
-                         .and_then(|(login_user, row)| {
-                             let owner: i32 = row.get(0);
-                             if let Some(login_user) = login_user {
-                                 if owner != login_user.to_raw() {
-                                     Err(crate::Error::Custom(hyper::Response::builder()
-                                                              .status(hyper::StatusCode::FORBIDDEN)
-                                                              .body("That's not your redirect".into())))
-                                 } else {
-                                     Ok(())
-                                 }
-                             } else {
-                                 Err(crate::Error::Custom(hyper::Response::builder()
-                                                          .status(hyper::StatusCode::UNAUTHORIZED)
-                                                          .body("Login is required to access redirects".into())))
-                             }
-                         })
-                         .and_then(move |_| {
+                let settings = server_state.settings.clone();
+                Box::new(ensure_owner(&db_pool, server_state, &req, id, crate::api_keys::Scope::RedirectsWrite)
+                         .and_then(move |redirect| {
                              req.into_body()
                                  .concat2()
                                  .map_err(crate::Error::internal)
@@ -176,21 +269,102 @@ fn redirect_path(
                                          .map_err(crate::Error::internal)
                                  })
                              .and_then(move |body: RedirectPatchBody| {
+                                 let new_host = match body.host.map(|host| crate::validation::normalize_redirect_host(&host, &settings)) {
+                                     Some(Ok(host)) if host != redirect.host => Some(host),
+                                     Some(Err(err)) => return futures::future::Either::A(futures::future::err(err.into())),
+                                     _ => None,
+                                 };
+
+                                 // a new host or mode could make the existing destination invalid too
+                                 let host = new_host.as_ref().unwrap_or(&redirect.host);
+                                 let destination = body.destination.as_ref().unwrap_or(&redirect.destination);
+                                 let path_mode = body.path_mode.unwrap_or(redirect.path_mode);
+                                 let mut new_destination = None;
+                                 if body.destination.is_some() || new_host.is_some() || body.path_mode.is_some() {
+                                     match crate::validation::normalize_destination(destination, host, path_mode, &settings) {
+                                         Ok(destination) => {
+                                             if body.destination.is_some() {
+                                                 new_destination = Some(destination);
+                                             }
+                                         }
+                                         Err(err) => return futures::future::Either::A(futures::future::err(err.into())),
+                                     }
+                                 }
+
+                                 if let Some(status_code) = body.status_code {
+                                     if let Err(err) = crate::validation::validate_status_code(status_code) {
+                                         return futures::future::Either::A(futures::future::err(err.into()));
+                                     }
+                                 }
+
+                                 if let Some(ref holding_response) = body.holding_response {
+                                     if let Some(status_code) = holding_response.status_code {
+                                         if let Err(err) = crate::validation::validate_holding_status_code(status_code) {
+                                             return futures::future::Either::A(futures::future::err(err.into()));
+                                         }
+                                     }
+                                     if let Some(ref message) = holding_response.message {
+                                         if let Err(err) = crate::validation::validate_holding_message(message) {
+                                             return futures::future::Either::A(futures::future::err(err.into()));
+                                         }
+                                     }
+                                 }
+
+                                 // pending scheduled changes were only checked against the old host and mode
+                                 let schedule_check = if new_host.is_some() || body.path_mode.is_some() {
+                                     Some((host.clone(), path_mode))
+                                 } else {
+                                     None
+                                 };
+
+                                 let conflict_host = new_host.clone();
+
                                  let mut changes: HashMap<&str, Box<dyn tokio_postgres::types::ToSql + Send + Sync>> = HashMap::new();
-                                 if let Some(destination) = body.destination {
+                                 if let Some(host) = new_host {
+                                     changes.insert("host", Box::new(host));
+                                 }
+                                 if let Some(destination) = new_destination {
                                      changes.insert("destination", Box::new(destination));
                                  }
+                                 if let Some(status_code) = body.status_code {
+                                     changes.insert("status_code", Box::new(status_code));
+                                 }
+                                 if let Some(path_mode) = body.path_mode {
+                                     changes.insert("path_mode", Box::new(path_mode.as_str()));
+                                 }
+                                 if let Some(enabled) = body.enabled {
+                                     changes.insert("enabled", Box::new(enabled));
+                                 }
+                                 if let Some(holding_response) = body.holding_response {
+                                     if let Some(status_code) = holding_response.status_code {
+                                         changes.insert("holding_status_code", Box::new(status_code));
+                                     }
+                                     if let Some(message) = holding_response.message {
+                                         changes.insert("holding_message", Box::new(message));
+                                     }
+                                 }
                                  if changes.is_empty() {
                                      futures::future::Either::A(futures::future::ok(()))
                                  } else {
                                      let mut values: Vec<Box<dyn tokio_postgres::types::ToSql + Send + Sync>> = vec![Box::new(id)];
 
-                                     let sql = format!("UPDATE redirects SET {} WHERE id=$1", changes.into_iter().map(|(key, value)| {
+                                     let mut assignments: Vec<_> = changes.into_iter().map(|(key, value)| {
                                          values.push(value);
                                          format!("\"{}\" = ${}", key, values.len())
-                                     }).collect::<Vec<_>>().join(", "));
+                                     }).collect();
+                                     if conflict_host.is_some() {
+                                         // the old certificate and DNS check don't apply to the new host
+                                         assignments.push("tls_cert=NULL, tls_privkey=NULL, acme_failed=FALSE, record_confirmed=FALSE".to_owned());
+                                     }
+
+                                     let sql = format!("UPDATE redirects SET {} WHERE id=$1", assignments.join(", "));
+
+                                     let schedule_check = match schedule_check {
+                                         Some((host, path_mode)) => futures::future::Either::A(super::redirect_schedule::check_pending_changes(&db_pool, id, host, path_mode, settings.clone())),
+                                         None => futures::future::Either::B(futures::future::ok(())),
+                                     };
 
-                                 futures::future::Either::B(db_pool.run(move |mut conn| {
+                                 futures::future::Either::B(schedule_check.and_then(move |_| db_pool.run(move |mut conn| {
                                      conn.prepare(&sql)
                                          .then(|res| tack_on(res, conn))
                                          .and_then(move |(stmt, mut conn)| {
@@ -200,8 +374,12 @@ fn redirect_path(
                                          })
                                  })
                                                             .map(|_| ())
-                                                            .map_err(ErrorWrapper::from)
-                                                            .map_err(crate::Error::internal))
+                                                            .map_err(move |err| {
+                                                                match conflict_host {
+                                                                    Some(ref host) if crate::is_unique_violation(&err) => host_conflict(host),
+                                                                    _ => crate::Error::internal(ErrorWrapper::from(err)),
+                                                                }
+                                                            })))
                                  }
                              })
                          })
@@ -211,8 +389,43 @@ fn redirect_path(
                                  .map_err(crate::Error::internal)
                          }))
             },
+            hyper::Method::DELETE => {
+                let db_pool = db_pool.clone();
+                Box::new(ensure_owner(&db_pool, server_state, &req, id, crate::api_keys::Scope::RedirectsWrite)
+                         .and_then(move |redirect| {
+                             // the owner is checked again here, since it may have changed through a claim
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("WITH target AS (SELECT id FROM redirects WHERE id=$1 AND owner=$2), rules AS (DELETE FROM redirect_rules WHERE redirect_id IN (SELECT id FROM target)), schedule AS (DELETE FROM scheduled_changes WHERE redirect_id IN (SELECT id FROM target)), history AS (DELETE FROM redirect_history WHERE redirect_id IN (SELECT id FROM target)) DELETE FROM redirects WHERE id=$1 AND owner=$2")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.execute(&stmt, &[&id, &redirect.owner.to_raw()])
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|count| {
+                             if count > 0 {
+                                 hyper::Response::builder()
+                                     .status(hyper::StatusCode::NO_CONTENT)
+                                     .body(hyper::Body::empty())
+                                     .map_err(crate::Error::internal)
+                             } else {
+                                 Err(crate::Error::Custom(hyper::Response::builder()
+                                                          .status(hyper::StatusCode::NOT_FOUND)
+                                                          .body("No such redirect".into())))
+                             }
+                         }))
+            },
             _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
         }
+    } else if let Some(path) = crate::consume_path(path, "rules/") {
+        super::redirect_rules::rules_path(db_pool, server_state, req, id, path)
+    } else if let Some(path) = crate::consume_path(path, "schedule/") {
+        super::redirect_schedule::schedule_path(db_pool, server_state, req, id, path)
+    } else if let Some(path) = crate::consume_path(path, "history/") {
+        super::redirect_schedule::history_path(db_pool, server_state, req, id, path)
     } else {
         Box::new(futures::future::err(crate::Error::NotFound))
     }
@@ -6,6 +6,7 @@ use crate::ServerState;
 #[derive(Serialize)]
 struct Output<'a> {
     free_visits: i32,
+    require_verified_email: bool,
     redirect_host: &'a Option<String>,
     stripe_publishable_key: &'a Option<String>,
 }
@@ -21,6 +22,7 @@ pub fn settings(
                 let settings = &*server_state.settings;
                 let output = Output {
                     free_visits: settings.free_visits,
+                    require_verified_email: settings.require_verified_email,
                     redirect_host: &settings.redirect_host,
                     stripe_publishable_key: &settings.stripe_publishable_key,
                 };
@@ -0,0 +1,189 @@
+use futures::{Future, IntoFuture, Stream};
+use serde_derive::{Deserialize, Serialize};
+
+use super::ensure_me;
+use crate::api_keys::Scope;
+use crate::{tack_on, DbPool, ErrorWrapper, UserID};
+
+#[derive(Deserialize)]
+struct APIKeyCreateBody {
+    name: String,
+    scopes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct APIKeyInfo {
+    id: i32,
+    name: String,
+    scopes: Vec<String>,
+    created: chrono::NaiveDateTime,
+    last_used: Option<chrono::NaiveDateTime>,
+}
+
+pub fn api_keys_path(
+    db_pool: &DbPool,
+    req: hyper::Request<hyper::Body>,
+    user_id: UserID,
+    is_me: bool,
+    path: &str,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    if path.is_empty() {
+        match *req.method() {
+            hyper::Method::GET => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then(move |_| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("SELECT id, name, scopes, created, last_used FROM api_keys WHERE user_id=$1 ORDER BY created")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&user_id.to_raw()])
+                                             .collect()
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .map(|rows| {
+                                     rows.into_iter().map(|row| {
+                                         APIKeyInfo {
+                                             id: row.get(0),
+                                             name: row.get(1),
+                                             scopes: row.get(2),
+                                             created: row.get(3),
+                                             last_used: row.get(4),
+                                         }
+                                     }).collect::<Vec<_>>()
+                                 })
+                         })
+                         .and_then(|result| {
+                             serde_json::to_vec(&result)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            hyper::Method::POST => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then(move |_| {
+                             req.into_body()
+                                 .concat2()
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             serde_json::from_slice(&body)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body: APIKeyCreateBody| {
+                             for scope in &body.scopes {
+                                 if scope.parse::<Scope>().is_err() {
+                                     return Err(crate::Error::Custom(hyper::Response::builder()
+                                                                     .status(hyper::StatusCode::BAD_REQUEST)
+                                                                     .body(format!("Unknown scope: {}", scope).into())));
+                                 }
+                             }
+
+                             Ok(body)
+                         })
+                         .and_then(move |body| {
+                             // the key itself is only ever returned here
+                             let key = crate::api_keys::generate();
+                             let key_hash = crate::api_keys::hash(&key);
+
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("INSERT INTO api_keys (user_id, name, key_hash, scopes, created) VALUES ($1, $2, $3, $4, localtimestamp) RETURNING id")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&user_id.to_raw(), &body.name, &key_hash, &body.scopes])
+                                             .into_future()
+                                             .map(|(res, _)| res)
+                                             .map_err(|(err, _)| err)
+                                             .map(move |row| -> i32 {
+                                                 row.expect("RETURNING clause failed?").get(0)
+                                             })
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .map(move |id| (id, key))
+                         })
+                         .and_then(|(id, key)| {
+                             serde_json::to_vec(&serde_json::json!({
+                                 "id": id,
+                                 "key": key,
+                             }))
+                             .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .status(hyper::StatusCode::CREATED)
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else if let Some((segment, path)) = crate::consume_path_segment(path) {
+        if !path.is_empty() {
+            return Box::new(futures::future::err(crate::Error::NotFound));
+        }
+
+        let key_id = match segment.parse::<i32>() {
+            Ok(key_id) => key_id,
+            Err(_err) => {
+                return Box::new(futures::future::err(crate::Error::Custom(
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::BAD_REQUEST)
+                        .body("Invalid API key ID".into()),
+                )));
+            }
+        };
+
+        match *req.method() {
+            hyper::Method::DELETE => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then(move |_| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("DELETE FROM api_keys WHERE id=$1 AND user_id=$2")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.execute(&stmt, &[&key_id, &user_id.to_raw()])
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|count| {
+                             if count > 0 {
+                                 hyper::Response::builder()
+                                     .status(hyper::StatusCode::NO_CONTENT)
+                                     .body(hyper::Body::empty())
+                                     .map_err(crate::Error::internal)
+                             } else {
+                                 Err(crate::Error::Custom(hyper::Response::builder()
+                                                          .status(hyper::StatusCode::NOT_FOUND)
+                                                          .body("No such API key".into())))
+                             }
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else {
+        Box::new(futures::future::err(crate::Error::NotFound))
+    }
+}
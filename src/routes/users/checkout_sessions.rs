@@ -1,6 +1,6 @@
 use futures::{Future, IntoFuture, Stream};
 
-use super::ensure_me;
+use super::{ensure_me, ensure_verified};
 use crate::{tack_on, DbPool, ErrorWrapper, ServerState, UserID, STRIPE_API};
 
 pub fn checkout_sessions_path(
@@ -27,9 +27,18 @@ pub fn checkout_sessions_path(
                 let db_pool = db_pool.clone();
                 let http_client = server_state.http_client.clone();
 
-                Box::new(req.into_body()
-                         .concat2()
-                         .map_err(crate::Error::internal)
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then({
+                             let db_pool = db_pool.clone();
+                             let server_state = server_state.clone();
+                             move |_| ensure_verified(&db_pool, &server_state.settings, user_id)
+                         })
+                         .and_then(move |_| {
+                             req.into_body()
+                                 .concat2()
+                                 .map_err(crate::Error::internal)
+                         })
                          .and_then(|body| {
                              serde_json::from_slice(&body)
                                  .map_err(crate::Error::internal)
@@ -0,0 +1,192 @@
+use futures::{Future, IntoFuture, Stream};
+use serde_derive::Deserialize;
+use std::sync::Arc;
+
+use super::ensure_me;
+use crate::{tack_on, DbPool, ErrorWrapper, HttpClient, ServerState, UserID, STRIPE_API};
+
+#[derive(Deserialize)]
+struct UserDeleteBody {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct StripeSubscription {
+    id: String,
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct StripeCheckoutSession {
+    subscription: Option<StripeSubscription>,
+}
+
+fn stripe_request(
+    http_client: &HttpClient,
+    req: Result<hyper::Request<hyper::Body>, http::Error>,
+) -> impl Future<Item = hyper::Chunk, Error = crate::Error> + Send {
+    let http_client = http_client.clone();
+
+    req.map_err(crate::Error::internal)
+        .into_future()
+        .and_then(move |req| http_client.request(req).map_err(crate::Error::internal))
+        .and_then(|res| {
+            let status = res.status();
+            res.into_body()
+                .concat2()
+                .map_err(crate::Error::internal)
+                .and_then(move |body| {
+                    if status.is_success() {
+                        Ok(body)
+                    } else {
+                        Err(crate::Error::internal(ErrorWrapper::Text(format!("Received error from stripe: {:?}", body))))
+                    }
+                })
+        })
+}
+
+/// Cancels any subscription started through the given checkout sessions.
+fn cancel_subscriptions(
+    server_state: &ServerState,
+    stripe_ids: Vec<String>,
+) -> Box<dyn Future<Item = (), Error = crate::Error> + Send> {
+    if stripe_ids.is_empty() {
+        return Box::new(futures::future::ok(()));
+    }
+
+    let auth_header = match server_state.settings.stripe_secret_key.as_ref() {
+        Some(key) => format!("Basic {}", base64::encode(&format!("{}:", key))),
+        None => {
+            return Box::new(futures::future::err(crate::Error::internal(ErrorWrapper::Text("Missing Stripe secret key".to_owned()))));
+        }
+    };
+    let http_client = server_state.http_client.clone();
+
+    Box::new(futures::future::join_all(stripe_ids.into_iter().map(move |stripe_id| {
+        let auth_header = auth_header.clone();
+        let http_client = http_client.clone();
+
+        stripe_request(&http_client, hyper::Request::get(format!("{}v1/checkout/sessions/{}?expand[]=subscription", STRIPE_API, stripe_id))
+                       .header(hyper::header::AUTHORIZATION, &auth_header as &str)
+                       .body(hyper::Body::empty()))
+            .and_then(|body| {
+                serde_json::from_slice(&body)
+                    .map_err(crate::Error::internal)
+            })
+            .and_then(move |session: StripeCheckoutSession| {
+                match session.subscription {
+                    // ended subscriptions can't be canceled again
+                    Some(ref subscription) if subscription.status != "canceled" && subscription.status != "incomplete_expired" => {
+                        futures::future::Either::A(stripe_request(&http_client, hyper::Request::delete(format!("{}v1/subscriptions/{}", STRIPE_API, subscription.id))
+                                                                  .header(hyper::header::AUTHORIZATION, &auth_header as &str)
+                                                                  .body(hyper::Body::empty()))
+                                                   .map(|_| ()))
+                    }
+                    _ => futures::future::Either::B(futures::future::ok(())),
+                }
+            })
+    }))
+             .map(|_| ()))
+}
+
+pub fn delete_user(
+    cpupool: &Arc<futures_cpupool::CpuPool>,
+    db_pool: &DbPool,
+    server_state: &ServerState,
+    req: hyper::Request<hyper::Body>,
+    user_id: UserID,
+    is_me: bool,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    let cpupool = cpupool.clone();
+    let db_pool = db_pool.clone();
+    let server_state = server_state.clone();
+
+    Box::new(ensure_me(is_me)
+             .into_future()
+             .and_then(move |_| {
+                 req.into_body()
+                     .concat2()
+                     .map_err(crate::Error::internal)
+             })
+             .and_then(|body| {
+                 serde_json::from_slice(&body)
+                     .map_err(crate::Error::internal)
+             })
+             .and_then({
+                 let db_pool = db_pool.clone();
+                 move |body: UserDeleteBody| {
+                     db_pool.run(move |mut conn| {
+                         conn.prepare("SELECT passhash FROM users WHERE id=$1")
+                             .then(|res| tack_on(res, conn))
+                             .and_then(move |(stmt, mut conn)| {
+                                 conn.query(&stmt, &[&user_id.to_raw()])
+                                     .into_future()
+                                     .map(|(res, _)| res)
+                                     .map_err(|(err, _)| err)
+                                     .then(|res| tack_on(res, conn))
+                             })
+                     })
+                     .map_err(ErrorWrapper::from)
+                         .map_err(crate::Error::internal)
+                         .and_then(|row| {
+                             row.ok_or_else(|| crate::Error::internal(ErrorWrapper::Text("Missing user somehow".to_owned())))
+                         })
+                         .and_then(move |row| {
+                             let passhash: String = row.get(0);
+
+                             cpupool.spawn_fn(move || {
+                                 crate::passwords::verify(&body.password, &passhash)
+                             })
+                         })
+                         .and_then(|correct| {
+                             if correct {
+                                 Ok(())
+                             } else {
+                                 Err(crate::Error::Custom(hyper::Response::builder()
+                                                          .status(hyper::StatusCode::UNAUTHORIZED)
+                                                          .body("Incorrect password".into())))
+                             }
+                         })
+                 }
+             })
+             .and_then({
+                 let db_pool = db_pool.clone();
+                 move |_| {
+                     db_pool.run(move |mut conn| {
+                         conn.prepare("SELECT stripe_id FROM subscription_checkout_sessions WHERE user_id=$1 AND stripe_id IS NOT NULL")
+                             .then(|res| tack_on(res, conn))
+                             .and_then(move |(stmt, mut conn)| {
+                                 conn.query(&stmt, &[&user_id.to_raw()])
+                                     .map(|row| -> String { row.get(0) })
+                                     .collect()
+                                     .then(|res| tack_on(res, conn))
+                             })
+                     })
+                     .map_err(ErrorWrapper::from)
+                         .map_err(crate::Error::internal)
+                 }
+             })
+             .and_then(move |stripe_ids| {
+                 // billing is stopped first, so a failure there leaves the account intact to retry
+                 cancel_subscriptions(&server_state, stripe_ids)
+             })
+             .and_then(move |_| {
+                 // a single statement, so everything is removed in one transaction or not at all
+                 db_pool.run(move |mut conn| {
+                     conn.prepare("WITH verifications AS (DELETE FROM email_verifications WHERE user_id=$1), resets AS (DELETE FROM password_resets WHERE user_id=$1), keys AS (DELETE FROM api_keys WHERE user_id=$1), recovery_codes AS (DELETE FROM totp_recovery_codes WHERE user_id=$1), sessions AS (DELETE FROM logins WHERE user_id=$1), rules AS (DELETE FROM redirect_rules WHERE redirect_id IN (SELECT id FROM redirects WHERE owner=$1)), schedule AS (DELETE FROM scheduled_changes WHERE redirect_id IN (SELECT id FROM redirects WHERE owner=$1)), history AS (DELETE FROM redirect_history WHERE redirect_id IN (SELECT id FROM redirects WHERE owner=$1)), owned_redirects AS (DELETE FROM redirects WHERE owner=$1), claims AS (DELETE FROM redirect_claims WHERE user_id=$1), checkout_sessions AS (DELETE FROM subscription_checkout_sessions WHERE user_id=$1) DELETE FROM users WHERE id=$1")
+                         .then(|res| tack_on(res, conn))
+                         .and_then(move |(stmt, mut conn)| {
+                             conn.execute(&stmt, &[&user_id.to_raw()])
+                                 .then(|res| tack_on(res, conn))
+                         })
+                 })
+                 .map_err(ErrorWrapper::from)
+                     .map_err(crate::Error::internal)
+             })
+             .and_then(|_| {
+                 hyper::Response::builder()
+                     .status(hyper::StatusCode::NO_CONTENT)
+                     .body(hyper::Body::empty())
+                     .map_err(crate::Error::internal)
+             }))
+}
@@ -0,0 +1,212 @@
+use futures::{Future, IntoFuture, Stream};
+use serde_derive::Serialize;
+
+use super::{ensure_me, HoldingResponse, RedirectInfo};
+use crate::path_mode::PathMode;
+use crate::routes::redirect_rules::RuleInfo;
+use crate::routes::redirect_schedule::ScheduledChangeInfo;
+use crate::routes::redirects::{RedirectInfoExpanded, RedirectTLSInfo};
+use crate::{tack_on, DbPool, ErrorWrapper, UserID};
+
+#[derive(Serialize)]
+struct ExportLoginInfo {
+    created: chrono::NaiveDateTime,
+    last_used: Option<chrono::NaiveDateTime>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportCheckoutSessionInfo {
+    id: i32,
+    tier_id: i32,
+    timestamp: chrono::NaiveDateTime,
+    stripe_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExportAPIKeyInfo {
+    name: String,
+    scopes: Vec<String>,
+    created: chrono::NaiveDateTime,
+    last_used: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+struct ExportRuleInfo {
+    redirect_id: i32,
+    #[serde(flatten)]
+    rule: RuleInfo,
+}
+
+#[derive(Serialize)]
+struct ExportScheduledChangeInfo {
+    redirect_id: i32,
+    #[serde(flatten)]
+    change: ScheduledChangeInfo,
+}
+
+#[derive(Serialize)]
+struct ExportHistoryInfo {
+    redirect_id: i32,
+    previous_destination: String,
+    destination: String,
+    scheduled_at: chrono::DateTime<chrono::Utc>,
+    applied_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+struct UserExport {
+    user: serde_json::Value,
+    redirects: Vec<RedirectInfoExpanded>,
+    redirect_rules: Vec<ExportRuleInfo>,
+    scheduled_changes: Vec<ExportScheduledChangeInfo>,
+    redirect_history: Vec<ExportHistoryInfo>,
+    logins: Vec<ExportLoginInfo>,
+    checkout_sessions: Vec<ExportCheckoutSessionInfo>,
+    api_keys: Vec<ExportAPIKeyInfo>,
+}
+
+fn query_rows(
+    db_pool: &DbPool,
+    sql: &'static str,
+    user_id: UserID,
+) -> impl Future<Item = Vec<tokio_postgres::Row>, Error = crate::Error> + Send {
+    db_pool
+        .run(move |mut conn| {
+            conn.prepare(sql)
+                .then(|res| tack_on(res, conn))
+                .and_then(move |(stmt, mut conn)| {
+                    conn.query(&stmt, &[&user_id.to_raw()])
+                        .collect()
+                        .then(|res| tack_on(res, conn))
+                })
+        })
+        .map_err(ErrorWrapper::from)
+        .map_err(crate::Error::internal)
+}
+
+pub fn export_path(
+    db_pool: &DbPool,
+    req: hyper::Request<hyper::Body>,
+    user_id: UserID,
+    is_me: bool,
+    path: &str,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    if !path.is_empty() {
+        return Box::new(futures::future::err(crate::Error::NotFound));
+    }
+
+    match *req.method() {
+        hyper::Method::GET => {
+            let db_pool = db_pool.clone();
+
+            Box::new(ensure_me(is_me)
+                     .into_future()
+                     .and_then(move |_| {
+                         // secrets are left out, everything else in the row is included as-is
+                         query_rows(&db_pool, "SELECT (to_jsonb(users) - 'passhash' - 'totp_secret')::TEXT FROM users WHERE id=$1", user_id)
+                             .join5(
+                                 query_rows(&db_pool, "SELECT id, host, destination, cache_visit_count_total, cache_visit_count_month, acme_failed, (tls_cert IS NOT NULL AND tls_privkey IS NOT NULL), record_confirmed, status_code, path_mode, enabled, holding_status_code, holding_message FROM redirects WHERE owner=$1 ORDER BY id", user_id),
+                                 query_rows(&db_pool, "SELECT created, last_used, user_agent, ip FROM logins WHERE user_id=$1 ORDER BY created", user_id),
+                                 query_rows(&db_pool, "SELECT id, tier_id, timestamp, stripe_id FROM subscription_checkout_sessions WHERE user_id=$1 ORDER BY timestamp", user_id),
+                                 query_rows(&db_pool, "SELECT name, scopes, created, last_used FROM api_keys WHERE user_id=$1 ORDER BY created", user_id),
+                             )
+                             .join3(
+                                 query_rows(&db_pool, "SELECT redirect_rules.id, position, match_type, pattern, redirect_rules.destination, redirect_rules.status_code, redirect_id FROM redirect_rules, redirects WHERE redirects.id=redirect_rules.redirect_id AND redirects.owner=$1 ORDER BY redirect_id, position, redirect_rules.id", user_id),
+                                 query_rows(&db_pool, "SELECT scheduled_changes.id, at, scheduled_changes.destination, redirect_id FROM scheduled_changes, redirects WHERE redirects.id=scheduled_changes.redirect_id AND redirects.owner=$1 ORDER BY at, scheduled_changes.id", user_id),
+                                 query_rows(&db_pool, "SELECT redirect_id, previous_destination, redirect_history.destination, scheduled_at, applied_at FROM redirect_history, redirects WHERE redirects.id=redirect_history.redirect_id AND redirects.owner=$1 ORDER BY applied_at, scheduled_at", user_id),
+                             )
+                     })
+                     .and_then(|((user, redirects, logins, checkout_sessions, api_keys), redirect_rules, scheduled_changes, redirect_history)| {
+                         let user = user.into_iter().next()
+                             .ok_or_else(|| crate::Error::internal(ErrorWrapper::Text("Missing user somehow".to_owned())))?;
+                         let user: String = user.get(0);
+
+                         let export = UserExport {
+                             user: serde_json::from_str(&user).map_err(crate::Error::internal)?,
+                             redirects: redirects.into_iter().map(|row| {
+                                 RedirectInfoExpanded {
+                                     base: RedirectInfo {
+                                         id: row.get(0),
+                                         host: row.get(1),
+                                         destination: row.get(2),
+                                         visits_total: row.get(3),
+                                         visits_month: row.get(4),
+                                         status_code: row.get(8),
+                                         path_mode: PathMode::from_column(row.get(9)),
+                                         enabled: row.get(10),
+                                         holding_response: HoldingResponse {
+                                             status_code: row.get(11),
+                                             message: row.get(12),
+                                         },
+                                     },
+                                     tls: RedirectTLSInfo::new(row.get(1), row.get(6), row.get(5)),
+                                     record_confirmed: row.get(7),
+                                 }
+                             }).collect(),
+                             redirect_rules: redirect_rules.iter().map(|row| {
+                                 ExportRuleInfo {
+                                     redirect_id: row.get(6),
+                                     rule: RuleInfo::from_row(row),
+                                 }
+                             }).collect(),
+                             scheduled_changes: scheduled_changes.into_iter().map(|row| {
+                                 ExportScheduledChangeInfo {
+                                     redirect_id: row.get(3),
+                                     change: ScheduledChangeInfo {
+                                         id: row.get(0),
+                                         at: row.get(1),
+                                         destination: row.get(2),
+                                     },
+                                 }
+                             }).collect(),
+                             redirect_history: redirect_history.into_iter().map(|row| {
+                                 ExportHistoryInfo {
+                                     redirect_id: row.get(0),
+                                     previous_destination: row.get(1),
+                                     destination: row.get(2),
+                                     scheduled_at: row.get(3),
+                                     applied_at: row.get(4),
+                                 }
+                             }).collect(),
+                             logins: logins.into_iter().map(|row| {
+                                 ExportLoginInfo {
+                                     created: row.get(0),
+                                     last_used: row.get(1),
+                                     user_agent: row.get(2),
+                                     ip: row.get(3),
+                                 }
+                             }).collect(),
+                             checkout_sessions: checkout_sessions.into_iter().map(|row| {
+                                 ExportCheckoutSessionInfo {
+                                     id: row.get(0),
+                                     tier_id: row.get(1),
+                                     timestamp: row.get(2),
+                                     stripe_id: row.get(3),
+                                 }
+                             }).collect(),
+                             api_keys: api_keys.into_iter().map(|row| {
+                                 ExportAPIKeyInfo {
+                                     name: row.get(0),
+                                     scopes: row.get(1),
+                                     created: row.get(2),
+                                     last_used: row.get(3),
+                                 }
+                             }).collect(),
+                         };
+
+                         serde_json::to_vec(&export)
+                             .map_err(crate::Error::internal)
+                     })
+                     .and_then(|body| {
+                         hyper::Response::builder()
+                             .header(hyper::header::CONTENT_TYPE, "application/json")
+                             .header(hyper::header::CONTENT_DISPOSITION, "attachment; filename=\"export.json\"")
+                             .body(body.into())
+                             .map_err(crate::Error::internal)
+                     }))
+        }
+        _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+    }
+}
@@ -0,0 +1,149 @@
+use futures::{Future, IntoFuture, Stream};
+use serde_derive::Serialize;
+
+use super::ensure_me;
+use crate::{tack_on, DbPool, ErrorWrapper, UserID};
+
+#[derive(Serialize)]
+struct LoginInfo {
+    id: i32,
+    created: chrono::NaiveDateTime,
+    last_used: Option<chrono::NaiveDateTime>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    current: bool,
+}
+
+pub fn logins_path(
+    db_pool: &DbPool,
+    req: hyper::Request<hyper::Body>,
+    user_id: UserID,
+    is_me: bool,
+    path: &str,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    let current_token = crate::get_auth_token(&req).and_then(Result::ok);
+
+    if path.is_empty() {
+        match *req.method() {
+            hyper::Method::GET => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then(move |_| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("SELECT id, created, last_used, user_agent, ip, token FROM logins WHERE user_id=$1 ORDER BY created DESC")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&user_id.to_raw()])
+                                             .collect()
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .map(move |rows| {
+                                     rows.into_iter().map(|row| {
+                                         let token: uuid::Uuid = row.get(5);
+                                         LoginInfo {
+                                             id: row.get(0),
+                                             created: row.get(1),
+                                             last_used: row.get(2),
+                                             user_agent: row.get(3),
+                                             ip: row.get(4),
+                                             current: Some(token) == current_token,
+                                         }
+                                     }).collect::<Vec<_>>()
+                                 })
+                         })
+                         .and_then(|result| {
+                             serde_json::to_vec(&result)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            hyper::Method::DELETE => {
+                // revoke every session except the one making this request
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then(move |_| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("DELETE FROM logins WHERE user_id=$1 AND token IS DISTINCT FROM $2")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.execute(&stmt, &[&user_id.to_raw(), &current_token])
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|_| {
+                             hyper::Response::builder()
+                                 .status(hyper::StatusCode::NO_CONTENT)
+                                 .body(hyper::Body::empty())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else if let Some((segment, path)) = crate::consume_path_segment(path) {
+        if !path.is_empty() {
+            return Box::new(futures::future::err(crate::Error::NotFound));
+        }
+
+        let login_id = match segment.parse::<i32>() {
+            Ok(login_id) => login_id,
+            Err(_err) => {
+                return Box::new(futures::future::err(crate::Error::Custom(
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::BAD_REQUEST)
+                        .body("Invalid login ID".into()),
+                )));
+            }
+        };
+
+        match *req.method() {
+            hyper::Method::DELETE => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then(move |_| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("DELETE FROM logins WHERE id=$1 AND user_id=$2")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.execute(&stmt, &[&login_id, &user_id.to_raw()])
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|count| {
+                             if count > 0 {
+                                 hyper::Response::builder()
+                                     .status(hyper::StatusCode::NO_CONTENT)
+                                     .body(hyper::Body::empty())
+                                     .map_err(crate::Error::internal)
+                             } else {
+                                 Err(crate::Error::Custom(hyper::Response::builder()
+                                                          .status(hyper::StatusCode::NOT_FOUND)
+                                                          .body("No such login".into())))
+                             }
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else {
+        Box::new(futures::future::err(crate::Error::NotFound))
+    }
+}
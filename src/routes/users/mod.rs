@@ -2,9 +2,18 @@ use futures::{Future, IntoFuture, Stream};
 use serde_derive::{Deserialize, Serialize};
 use std::sync::Arc;
 
+use crate::api_keys::Scope;
+use crate::path_mode::PathMode;
 use crate::{rd_login, tack_on, DbPool, ErrorWrapper, ServerState, UserID};
 
+mod api_keys;
 mod checkout_sessions;
+mod deletion;
+mod export;
+mod logins;
+mod password;
+mod redirect_claims;
+mod totp;
 
 #[derive(Deserialize)]
 struct SignupReqBody {
@@ -12,10 +21,36 @@ struct SignupReqBody {
     password: String,
 }
 
+#[derive(Deserialize)]
+struct UserPatchBody {
+    email: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct RedirectCreateReqBody {
     host: String,
     destination: String,
+    #[serde(default = "default_status_code")]
+    status_code: i32,
+    #[serde(default)]
+    path_mode: PathMode,
+}
+
+pub fn default_status_code() -> i32 {
+    302
+}
+
+#[derive(Serialize)]
+struct UserProfile<'a> {
+    id: UserID,
+    email: String,
+    verified: bool,
+    created: chrono::NaiveDateTime,
+    /// `None` while the tier cache hasn't caught up with the user's tier.
+    tier: Option<&'a crate::TierInfo>,
+    redirect_count: i64,
+    visits_month: i64,
+    visit_limit: Option<i32>,
 }
 
 enum UserIDOrMe {
@@ -30,6 +65,17 @@ pub struct RedirectInfo {
     pub destination: String,
     pub visits_total: Option<i32>,
     pub visits_month: Option<i32>,
+    pub status_code: i32,
+    pub path_mode: PathMode,
+    pub enabled: bool,
+    pub holding_response: HoldingResponse,
+}
+
+/// Served in place of the redirect while it is disabled.
+#[derive(Serialize)]
+pub struct HoldingResponse {
+    pub status_code: i32,
+    pub message: String,
 }
 
 impl std::str::FromStr for UserIDOrMe {
@@ -55,6 +101,7 @@ pub fn users(
             hyper::Method::POST => {
                 let cpupool = cpupool.clone();
                 let db_pool = db_pool.clone();
+                let server_state = server_state.clone();
 
                 Box::new(req.into_body()
                          .concat2()
@@ -66,39 +113,68 @@ pub fn users(
                          .and_then(move |body: SignupReqBody| {
                              let SignupReqBody { email, password } = body;
 
-                             cpupool.spawn_fn(move || {
-                                 bcrypt::hash(password, bcrypt::DEFAULT_COST)
+                             // checked before the account exists, since the verification link needs it
+                             if server_state.settings.frontend_host.is_none() {
+                                 return futures::future::Either::A(futures::future::err(crate::Error::internal(ErrorWrapper::Text("Missing frontend host".to_owned()))));
+                             }
+
+                             let hash_params = server_state.settings.hash_params.clone();
+
+                             futures::future::Either::B(cpupool.spawn_fn(move || {
+                                 crate::passwords::hash(&password, &hash_params)
                              })
-                             .map_err(|err| crate::Error::Internal(Box::new(err)))
                                  .and_then(move |passhash| {
+                                     let to = email.clone();
+
                                      db_pool.run(move |mut conn| {
-                                         conn.prepare("INSERT INTO users (email, passhash) VALUES ($1, $2) RETURNING id")
+                                         conn.prepare("INSERT INTO users (email, passhash) VALUES ($1, $2) ON CONFLICT (email) DO NOTHING RETURNING id")
                                              .then(|res| tack_on(res, conn))
                                              .and_then(move |(stmt, mut conn)| {
                                                  conn.query(&stmt, &[&email, &passhash])
                                                      .into_future()
                                                      .map(|(res, _)| res)
                                                      .map_err(|(err, _)| err)
-                                                     .and_then(|row| {
-                                                         let id: i32 = row.expect("RETURNING clause failed?").get(0);
-                                                         Ok(hyper::Response::builder()
-                                                             .body(id.to_string().into())
-                                                             .map_err(|err| crate::Error::Internal(Box::new(err))))
-                                                     })
+                                                     .map(|row| row.map(|row| -> i32 { row.get(0) }))
                                                  .then(|res| tack_on(res, conn))
                                              })
                                      })
                                      .map_err(ErrorWrapper::from)
                                          .map_err(|err| crate::Error::Internal(Box::new(err)))
-                                         .and_then(|x| x)
-                                 })
+                                         .and_then({
+                                             let db_pool = db_pool.clone();
+                                             move |id| {
+                                                 // either way a single mail goes out, so the response doesn't reveal which happened
+                                                 match id {
+                                                     Some(id) => futures::future::Either::A(super::email_verifications::send_verification(&db_pool, &server_state, UserID(id), to, false)),
+                                                     None => futures::future::Either::B(server_state.mailer.send(crate::mail::Message {
+                                                         to,
+                                                         subject: "Sign up attempt".to_owned(),
+                                                         body: "Someone tried to sign up with this email address, but you already have an account.\n\nIf this was you, log in or reset your password instead. Otherwise, you can ignore this message.".to_owned(),
+                                                     })),
+                                                 }
+                                                 .or_else(|err| -> Result<(), crate::Error> {
+                                                     // the account exists either way, and the verification email can be sent again
+                                                     if let crate::Error::Internal(err) = err {
+                                                         eprintln!("Failed to send signup email: {:?}", err);
+                                                     }
+                                                     Ok(())
+                                                 })
+                                             }
+                                         })
+                                 }))
+                         })
+                         .and_then(|_| {
+                             hyper::Response::builder()
+                                 .status(hyper::StatusCode::ACCEPTED)
+                                 .body(hyper::Body::empty())
+                                 .map_err(|err| crate::Error::Internal(Box::new(err)))
                          }))
             }
             _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
         }
     } else if let Some((segment, path)) = crate::consume_path_segment(path) {
         match segment.parse::<UserIDOrMe>() {
-            Ok(id_or_me) => user_path(db_pool, server_state, req, id_or_me, path),
+            Ok(id_or_me) => user_path(cpupool, db_pool, server_state, req, id_or_me, path),
             Err(_err) => Box::new(futures::future::err(crate::Error::Custom(
                 hyper::Response::builder()
                     .status(hyper::StatusCode::BAD_REQUEST)
@@ -122,17 +198,75 @@ pub fn ensure_me(is_me: bool) -> Result<(), crate::Error> {
     }
 }
 
+/// Fails if the server requires verified email addresses and this user's is not verified.
+pub fn ensure_verified(
+    db_pool: &DbPool,
+    settings: &crate::Settings,
+    user_id: UserID,
+) -> impl Future<Item = (), Error = crate::Error> + Send {
+    if settings.require_verified_email {
+        futures::future::Either::A(
+            db_pool
+                .run(move |mut conn| {
+                    conn.prepare("SELECT verified FROM users WHERE id=$1")
+                        .then(|res| tack_on(res, conn))
+                        .and_then(move |(stmt, mut conn)| {
+                            conn.query(&stmt, &[&user_id.to_raw()])
+                                .into_future()
+                                .map(|(res, _)| res)
+                                .map_err(|(err, _)| err)
+                                .map(|row| row.map(|row| -> bool { row.get(0) }))
+                                .then(|res| tack_on(res, conn))
+                        })
+                })
+                .map_err(ErrorWrapper::from)
+                .map_err(crate::Error::internal)
+                .and_then(|verified| match verified {
+                    Some(true) => Ok(()),
+                    Some(false) => Err(crate::Error::Custom(
+                        hyper::Response::builder()
+                            .status(hyper::StatusCode::FORBIDDEN)
+                            .body("Email address must be verified first".into()),
+                    )),
+                    None => Err(crate::Error::internal(ErrorWrapper::Text(
+                        "Missing user somehow".to_owned(),
+                    ))),
+                }),
+        )
+    } else {
+        futures::future::Either::B(futures::future::ok(()))
+    }
+}
+
+/// The API key scope needed for a path under a user, or `None` if it requires a login session.
+fn required_scope(method: &hyper::Method, path: &str) -> Option<Scope> {
+    if path == "redirects/" || path.starts_with("redirect_claims/") {
+        Some(if *method == hyper::Method::GET {
+            Scope::RedirectsRead
+        } else {
+            Scope::RedirectsWrite
+        })
+    } else if path == "subscription_tier/" || path.starts_with("checkout_sessions/") {
+        Some(Scope::Billing)
+    } else {
+        None
+    }
+}
+
 fn user_path(
+    cpupool: &Arc<futures_cpupool::CpuPool>,
     db_pool: &DbPool,
     server_state: &ServerState,
     req: hyper::Request<hyper::Body>,
     id_or_me: UserIDOrMe,
     path: &str,
 ) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    let scope = required_scope(req.method(), path);
+    let cpupool = cpupool.clone();
     let db_pool = db_pool.clone();
     let server_state = server_state.clone();
     let path = path.to_owned();
-    Box::new(rd_login(&db_pool, &req)
+    Box::new(rd_login(&db_pool, &server_state.settings, &req, scope)
              .and_then(move |login_user| {
                  match id_or_me {
                      UserIDOrMe::ID(id) => {
@@ -154,15 +288,148 @@ fn user_path(
                  if path.is_empty() {
                      return match *req.method() {
                          hyper::Method::GET => {
-                             Box::new(serde_json::to_vec(&serde_json::json!({"id": id}))
-                                      .map_err(|err| crate::Error::Internal(Box::new(err)))
+                             if !is_me {
+                                 // other users only get to see the ID
+                                 return Box::new(serde_json::to_vec(&serde_json::json!({"id": id}))
+                                                 .map_err(|err| crate::Error::Internal(Box::new(err)))
+                                                 .and_then(|body| {
+                                                     hyper::Response::builder()
+                                                         .header(hyper::header::CONTENT_TYPE, "application/json")
+                                                         .body(body.into())
+                                                         .map_err(|err| crate::Error::Internal(Box::new(err)))
+                                                 })
+                                                 .into_future());
+                             }
+
+                             Box::new(db_pool.run(move |mut conn| {
+                                 conn.prepare("SELECT email, verified, tier, created, (SELECT COUNT(*) FROM redirects WHERE owner=users.id), (SELECT COALESCE(SUM(cache_visit_count_month), 0)::BIGINT FROM redirects WHERE owner=users.id) FROM users WHERE id=$1")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&id.to_raw()])
+                                             .into_future()
+                                             .map(|(res, _)| res)
+                                             .map_err(|(err, _)| err)
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                                      .map_err(ErrorWrapper::from)
+                                      .map_err(crate::Error::internal)
+                                      .and_then(|row| {
+                                          row.ok_or_else(|| crate::Error::internal(ErrorWrapper::Text("Missing user somehow".to_owned())))
+                                      })
+                                      .and_then(move |row| {
+                                          let user_tier: i32 = row.get(2);
+                                          let tiers = server_state.tiers.read().unwrap();
+                                          let tier = tiers.iter().find(|tier| tier.id == user_tier);
+
+                                          let profile = UserProfile {
+                                              id,
+                                              email: row.get(0),
+                                              verified: row.get(1),
+                                              created: row.get(3),
+                                              tier,
+                                              redirect_count: row.get(4),
+                                              visits_month: row.get(5),
+                                              visit_limit: tier.map(|tier| tier.visit_limit),
+                                          };
+
+                                          serde_json::to_vec(&profile)
+                                              .map_err(crate::Error::internal)
+                                      })
                                       .and_then(|body| {
                                           hyper::Response::builder()
                                               .header(hyper::header::CONTENT_TYPE, "application/json")
                                               .body(body.into())
-                                              .map_err(|err| crate::Error::Internal(Box::new(err)))
+                                              .map_err(crate::Error::internal)
+                                      }))
+                         },
+                         hyper::Method::DELETE => deletion::delete_user(&cpupool, &db_pool, &server_state, req, id, is_me),
+                         hyper::Method::PATCH => {
+                             Box::new(ensure_me(is_me)
+                                      .into_future()
+                                      .and_then(move |_| {
+                                          req.into_body()
+                                              .concat2()
+                                              .map_err(crate::Error::internal)
+                                      })
+                                      .and_then(|body| {
+                                          serde_json::from_slice(&body)
+                                              .map_err(crate::Error::internal)
                                       })
-                                      .into_future())
+                                      .and_then({
+                                          let db_pool = db_pool.clone();
+                                          move |body: UserPatchBody| {
+                                              match body.email {
+                                                  Some(email) => futures::future::Either::A({
+                                                      let new_email = email.clone();
+
+                                                      // any earlier pending change is replaced, or cancelled if this is the current address
+                                                      db_pool.run(move |mut conn| {
+                                                          conn.prepare("WITH stale AS (DELETE FROM email_verifications WHERE user_id=$1 AND email_change) SELECT email, EXISTS(SELECT 1 FROM users WHERE email=$2 AND id<>$1) FROM users WHERE id=$1")
+                                                              .then(|res| tack_on(res, conn))
+                                                              .and_then(move |(stmt, mut conn)| {
+                                                                  conn.query(&stmt, &[&id.to_raw(), &new_email])
+                                                                      .into_future()
+                                                                      .map(|(res, _)| res)
+                                                                      .map_err(|(err, _)| err)
+                                                                      .then(|res| tack_on(res, conn))
+                                                              })
+                                                      })
+                                                      .map_err(ErrorWrapper::from)
+                                                          .map_err(crate::Error::internal)
+                                                          .map(move |row| (row, email))
+                                                          .and_then(|(row, email)| {
+                                                              row.ok_or_else(|| crate::Error::internal(ErrorWrapper::Text("Missing user somehow".to_owned())))
+                                                                  .map(|row| (row, email))
+                                                          })
+                                                      .and_then(|(row, email)| {
+                                                          let old_email: String = row.get(0);
+                                                          let taken: bool = row.get(1);
+                                                          if old_email == email {
+                                                              Ok(None)
+                                                          } else {
+                                                              Ok(Some((old_email, email, taken)))
+                                                          }
+                                                      })
+                                                  }),
+                                                  None => futures::future::Either::B(futures::future::ok(None)),
+                                              }
+                                          }
+                                      })
+                                      .and_then(move |change| {
+                                          match change {
+                                              Some((old_email, new_email, taken)) => {
+                                                  // the new address only takes effect once confirmed
+                                                  let notice = server_state.mailer.send(crate::mail::Message {
+                                                      to: old_email,
+                                                      subject: "Your email address is being changed".to_owned(),
+                                                      body: format!("Someone requested to change the email address on your account to {}. The change will take effect once it is confirmed from that address.\n\nIf this wasn't you, change your password immediately.", new_email),
+                                                  });
+
+                                                  // like signup, the response doesn't reveal whether the address is taken
+                                                  let confirmation = if taken {
+                                                      futures::future::Either::A(server_state.mailer.send(crate::mail::Message {
+                                                          to: new_email,
+                                                          subject: "Email change attempt".to_owned(),
+                                                          body: "Someone tried to change the email address on another account to this one, but you already have an account with it.\n\nYou can ignore this message.".to_owned(),
+                                                      }))
+                                                  } else {
+                                                      futures::future::Either::B(super::email_verifications::send_verification(&db_pool, &server_state, id, new_email, true))
+                                                  };
+
+                                                  futures::future::Either::A(notice
+                                                                             .join(confirmation)
+                                                                             .map(|_| hyper::StatusCode::ACCEPTED))
+                                              }
+                                              None => futures::future::Either::B(futures::future::ok(hyper::StatusCode::NO_CONTENT)),
+                                          }
+                                      })
+                                      .and_then(|status| {
+                                          hyper::Response::builder()
+                                              .status(status)
+                                              .body(hyper::Body::empty())
+                                              .map_err(crate::Error::internal)
+                                      }))
                          },
                          _ => Box::new(futures::future::err(crate::Error::InvalidMethod))
                      }
@@ -175,7 +442,7 @@ fn user_path(
                                           .into_future()
                                           .and_then(move |_| {
                                               db_pool.run(move |mut conn| {
-                                                  conn.prepare("SELECT id, host, destination, cache_visit_count_total, cache_visit_count_month FROM redirects WHERE owner=$1")
+                                                  conn.prepare("SELECT id, host, destination, cache_visit_count_total, cache_visit_count_month, status_code, path_mode, enabled, holding_status_code, holding_message FROM redirects WHERE owner=$1")
                                                       .then(|res| tack_on(res, conn))
                                                       .and_then(move |(stmt, mut conn)| {
                                                           conn.query(&stmt, &[&id.to_raw()])
@@ -193,6 +460,13 @@ fn user_path(
                                                               destination: row.get(2),
                                                               visits_total: row.get(3),
                                                               visits_month: row.get(4),
+                                                              status_code: row.get(5),
+                                                              path_mode: PathMode::from_column(row.get(6)),
+                                                              enabled: row.get(7),
+                                                              holding_response: HoldingResponse {
+                                                                  status_code: row.get(8),
+                                                                  message: row.get(9),
+                                                              },
                                                           }
                                                       }).collect::<Vec<_>>()
                                                   })
@@ -211,6 +485,11 @@ fn user_path(
                              hyper::Method::POST => {
                                  Box::new(ensure_me(is_me)
                                           .into_future()
+                                          .and_then({
+                                              let db_pool = db_pool.clone();
+                                              let server_state = server_state.clone();
+                                              move |_| ensure_verified(&db_pool, &server_state.settings, id)
+                                          })
                                           .and_then(move |_| {
                                               req.into_body()
                                                   .concat2()
@@ -220,11 +499,23 @@ fn user_path(
                                                           .map_err(|err| crate::Error::Internal(Box::new(err)))
                                                   })
                                               .and_then(move |body: RedirectCreateReqBody| {
+                                                  let settings = &server_state.settings;
+                                                  crate::validation::normalize_redirect_host(&body.host, settings)
+                                                      .and_then(|host| {
+                                                          let destination = crate::validation::normalize_destination(&body.destination, &host, body.path_mode, settings)?;
+                                                          crate::validation::validate_status_code(body.status_code)?;
+                                                          Ok((host, destination, body.status_code, body.path_mode))
+                                                      })
+                                                      .map_err(crate::Error::from)
+                                              })
+                                              .and_then(move |(host, destination, status_code, path_mode)| {
+                                                  let conflict_host = host.clone();
+
                                                   db_pool.run(move |mut conn| {
-                                                      conn.prepare("INSERT INTO redirects (host, destination, owner) VALUES ($1, $2, $3) RETURNING id")
+                                                      conn.prepare("INSERT INTO redirects (host, destination, owner, status_code, path_mode) VALUES ($1, $2, $3, $4, $5) RETURNING id")
                                                           .then(|res| tack_on(res, conn))
                                                           .and_then(move |(stmt, mut conn)| {
-                                                              conn.query(&stmt, &[&body.host, &body.destination, &id.0])
+                                                              conn.query(&stmt, &[&host, &destination, &id.0, &status_code, &path_mode.as_str()])
                                                                   .into_future()
                                                                   .map(|(res, _)| res)
                                                                   .map_err(|(err, _)| err)
@@ -237,8 +528,13 @@ fn user_path(
                                                               .then(|res| tack_on(res, conn))
                                                           })
                                                   })
-                                                  .map_err(ErrorWrapper::from)
-                                                      .map_err(|err| crate::Error::Internal(Box::new(err)))
+                                                  .map_err(move |err| {
+                                                      if crate::is_unique_violation(&err) {
+                                                          super::redirects::host_conflict(&conflict_host)
+                                                      } else {
+                                                          crate::Error::internal(ErrorWrapper::from(err))
+                                                      }
+                                                  })
                                                       .and_then(|x| x)
                                               })
                                           }))
@@ -295,8 +591,70 @@ fn user_path(
                              _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
                          }
                      }
+                 } else if let Some(path) = crate::consume_path(&path, "email_verification/") {
+                     if path.is_empty() {
+                         return match *req.method() {
+                             hyper::Method::POST => {
+                                 Box::new(ensure_me(is_me)
+                                          .into_future()
+                                          .and_then({
+                                              let db_pool = db_pool.clone();
+                                              move |_| {
+                                                  db_pool.run(move |mut conn| {
+                                                      conn.prepare("SELECT email, verified FROM users WHERE id=$1")
+                                                          .then(|res| tack_on(res, conn))
+                                                          .and_then(move |(stmt, mut conn)| {
+                                                              conn.query(&stmt, &[&id.to_raw()])
+                                                                  .into_future()
+                                                                  .map(|(res, _)| res)
+                                                                  .map_err(|(err, _)| err)
+                                                                  .then(|res| tack_on(res, conn))
+                                                          })
+                                                  })
+                                                  .map_err(ErrorWrapper::from)
+                                                      .map_err(crate::Error::internal)
+                                              }
+                                          })
+                                          .and_then(|row| {
+                                              row.ok_or_else(|| crate::Error::internal(ErrorWrapper::Text("Missing user somehow".to_owned())))
+                                          })
+                                          .and_then(|row| {
+                                              let verified: bool = row.get(1);
+                                              if verified {
+                                                  Err(crate::Error::Custom(hyper::Response::builder()
+                                                                           .status(hyper::StatusCode::CONFLICT)
+                                                                           .body("Email address is already verified".into())))
+                                              } else {
+                                                  Ok(row.get::<_, String>(0))
+                                              }
+                                          })
+                                          .and_then(move |email| {
+                                              super::email_verifications::send_verification(&db_pool, &server_state, id, email, false)
+                                          })
+                                          .and_then(|_| {
+                                              hyper::Response::builder()
+                                                  .status(hyper::StatusCode::NO_CONTENT)
+                                                  .body(hyper::Body::empty())
+                                                  .map_err(crate::Error::internal)
+                                          }))
+                             },
+                             _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+                         }
+                     }
+                 } else if let Some(path) = crate::consume_path(&path, "api_keys/") {
+                     return api_keys::api_keys_path(&db_pool, req, id, is_me, path);
                  } else if let Some(path) = crate::consume_path(&path, "checkout_sessions/") {
                      return checkout_sessions::checkout_sessions_path(&db_pool, &server_state, req, id, is_me, path);
+                 } else if let Some(path) = crate::consume_path(&path, "export/") {
+                     return export::export_path(&db_pool, req, id, is_me, path);
+                 } else if let Some(path) = crate::consume_path(&path, "logins/") {
+                     return logins::logins_path(&db_pool, req, id, is_me, path);
+                 } else if let Some(path) = crate::consume_path(&path, "password/") {
+                     return password::password_path(&cpupool, &db_pool, &server_state, req, id, is_me, path);
+                 } else if let Some(path) = crate::consume_path(&path, "redirect_claims/") {
+                     return redirect_claims::redirect_claims_path(&db_pool, &server_state, req, id, is_me, path);
+                 } else if let Some(path) = crate::consume_path(&path, "totp/") {
+                     return totp::totp_path(&cpupool, &db_pool, req, id, is_me, path);
                  }
                  Box::new(futures::future::err(crate::Error::NotFound))
              })
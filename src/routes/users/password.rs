@@ -0,0 +1,125 @@
+use futures::{Future, IntoFuture, Stream};
+use serde_derive::Deserialize;
+use std::sync::Arc;
+
+use super::ensure_me;
+use crate::{tack_on, DbPool, ErrorWrapper, ServerState, UserID};
+
+#[derive(Deserialize)]
+struct PasswordChangeBody {
+    current_password: String,
+    new_password: String,
+    #[serde(default)]
+    revoke_other_logins: bool,
+}
+
+pub fn password_path(
+    cpupool: &Arc<futures_cpupool::CpuPool>,
+    db_pool: &DbPool,
+    server_state: &ServerState,
+    req: hyper::Request<hyper::Body>,
+    user_id: UserID,
+    is_me: bool,
+    path: &str,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    if path.is_empty() {
+        match *req.method() {
+            hyper::Method::PATCH => {
+                let cpupool = cpupool.clone();
+                let db_pool = db_pool.clone();
+                let hash_params = server_state.settings.hash_params.clone();
+                let current_token = crate::get_auth_token(&req).and_then(Result::ok);
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then(move |_| {
+                             req.into_body()
+                                 .concat2()
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             serde_json::from_slice(&body)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then({
+                             let db_pool = db_pool.clone();
+                             move |body: PasswordChangeBody| {
+                                 db_pool.run(move |mut conn| {
+                                     conn.prepare("SELECT passhash FROM users WHERE id=$1")
+                                         .then(|res| tack_on(res, conn))
+                                         .and_then(move |(stmt, mut conn)| {
+                                             conn.query(&stmt, &[&user_id.to_raw()])
+                                                 .into_future()
+                                                 .map(|(res, _)| res)
+                                                 .map_err(|(err, _)| err)
+                                                 .then(|res| tack_on(res, conn))
+                                         })
+                                 })
+                                 .map_err(ErrorWrapper::from)
+                                     .map_err(crate::Error::internal)
+                                     .and_then(|row| {
+                                         row.ok_or_else(|| crate::Error::internal(ErrorWrapper::Text("Missing user somehow".to_owned())))
+                                     })
+                                 .map(move |row| (row.get::<_, String>(0), body))
+                             }
+                         })
+                         .and_then(move |(passhash, body)| {
+                             let PasswordChangeBody { current_password, new_password, revoke_other_logins } = body;
+
+                             cpupool.spawn_fn(move || {
+                                 crate::passwords::verify(&current_password, &passhash)
+                             })
+                                 .and_then(|correct| {
+                                     if !correct {
+                                         Err(crate::Error::Custom(hyper::Response::builder()
+                                                                  .status(hyper::StatusCode::UNAUTHORIZED)
+                                                                  .body("Incorrect password".into())))
+                                     } else {
+                                         Ok(())
+                                     }
+                                 })
+                             .and_then(move |_| {
+                                 cpupool.spawn_fn(move || {
+                                     crate::passwords::hash(&new_password, &hash_params)
+                                 })
+                             })
+                             .map(move |passhash| (passhash, revoke_other_logins))
+                         })
+                         .and_then(move |(passhash, revoke_other_logins)| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("UPDATE users SET passhash=$1 WHERE id=$2")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.execute(&stmt, &[&passhash, &user_id.to_raw()])
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                                     .and_then(move |(_, mut conn)| {
+                                         if revoke_other_logins {
+                                             futures::future::Either::A(conn.prepare("DELETE FROM logins WHERE user_id=$1 AND token IS DISTINCT FROM $2")
+                                                 .then(|res| tack_on(res, conn))
+                                                 .and_then(move |(stmt, mut conn)| {
+                                                     conn.execute(&stmt, &[&user_id.to_raw(), &current_token])
+                                                         .map(|_| ())
+                                                         .then(|res| tack_on(res, conn))
+                                                 }))
+                                         } else {
+                                             futures::future::Either::B(futures::future::ok(((), conn)))
+                                         }
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|_| {
+                             hyper::Response::builder()
+                                 .status(hyper::StatusCode::NO_CONTENT)
+                                 .body(hyper::Body::empty())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else {
+        Box::new(futures::future::err(crate::Error::NotFound))
+    }
+}
@@ -0,0 +1,352 @@
+use futures::{Future, IntoFuture, Stream};
+use serde_derive::{Deserialize, Serialize};
+
+use super::{ensure_me, ensure_verified};
+use crate::path_mode::PathMode;
+use crate::{tack_on, DbPool, ErrorWrapper, ServerState, UserID};
+
+/// Claims must be verified within this many days.
+const CLAIM_MAX_AGE_DAYS: i32 = 7;
+
+/// The TXT record proving control of a host is placed under this label.
+const RECORD_PREFIX: &str = "_redirect-dog-claim";
+
+#[derive(Deserialize)]
+struct ClaimCreateBody {
+    host: String,
+    destination: String,
+}
+
+#[derive(Serialize)]
+struct ClaimRecord {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct ClaimInfo {
+    id: i32,
+    host: String,
+    destination: String,
+    record: ClaimRecord,
+    created: chrono::NaiveDateTime,
+}
+
+/// Where the TXT record for a claim on `host` goes. A wildcard is proven through its base domain.
+fn record_name(host: &str) -> String {
+    let domain = crate::validation::wildcard_base(host).unwrap_or(host);
+
+    format!("{}.{}", RECORD_PREFIX, domain)
+}
+
+impl ClaimInfo {
+    fn new(id: i32, host: String, destination: String, token: String, created: chrono::NaiveDateTime) -> Self {
+        Self {
+            id,
+            record: ClaimRecord {
+                type_: "TXT",
+                name: record_name(&host),
+                value: token,
+            },
+            host,
+            destination,
+            created,
+        }
+    }
+}
+
+fn already_owned() -> crate::Error {
+    crate::Error::Custom(
+        hyper::Response::builder()
+            .status(hyper::StatusCode::CONFLICT)
+            .body("You already own the redirect for that host".into()),
+    )
+}
+
+fn no_such_claim() -> crate::Error {
+    crate::Error::Custom(
+        hyper::Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body("No such claim".into()),
+    )
+}
+
+pub fn redirect_claims_path(
+    db_pool: &DbPool,
+    server_state: &ServerState,
+    req: hyper::Request<hyper::Body>,
+    user_id: UserID,
+    is_me: bool,
+    path: &str,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    if path.is_empty() {
+        match *req.method() {
+            hyper::Method::GET => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then(move |_| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("SELECT id, host, destination, token, created FROM redirect_claims WHERE user_id=$1 AND created > localtimestamp - $2::INTEGER * INTERVAL '1 day' ORDER BY created")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&user_id.to_raw(), &CLAIM_MAX_AGE_DAYS])
+                                             .collect()
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .map(|rows| {
+                                     rows.into_iter().map(|row| {
+                                         ClaimInfo::new(row.get(0), row.get(1), row.get(2), row.get(3), row.get(4))
+                                     }).collect::<Vec<_>>()
+                                 })
+                         })
+                         .and_then(|result| {
+                             serde_json::to_vec(&result)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            hyper::Method::POST => {
+                let db_pool = db_pool.clone();
+                let settings = server_state.settings.clone();
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then({
+                             let db_pool = db_pool.clone();
+                             let settings = settings.clone();
+                             move |_| ensure_verified(&db_pool, &settings, user_id)
+                         })
+                         .and_then(move |_| {
+                             req.into_body()
+                                 .concat2()
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             serde_json::from_slice(&body)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(move |body: ClaimCreateBody| {
+                             crate::validation::normalize_redirect_host(&body.host, &settings)
+                                 .and_then(|host| {
+                                     crate::validation::normalize_destination(&body.destination, &host, PathMode::Discard, &settings)
+                                         .map(|destination| (host, destination))
+                                 })
+                                 .map_err(crate::Error::from)
+                         })
+                         .and_then(move |(host, destination)| {
+                             let token = uuid::Uuid::new_v4().to_simple().to_string();
+
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("INSERT INTO redirect_claims (user_id, host, destination, token, created) SELECT $1, $2, $3, $4, localtimestamp WHERE NOT EXISTS (SELECT 1 FROM redirects WHERE host=$2 AND owner=$1) RETURNING id, created")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&user_id.to_raw(), &host, &destination, &token])
+                                             .into_future()
+                                             .map(|(res, _)| res)
+                                             .map_err(|(err, _)| err)
+                                             .map(move |row| {
+                                                 row.map(|row| ClaimInfo::new(row.get(0), host, destination, token, row.get(1)))
+                                             })
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .and_then(|info| info.ok_or_else(already_owned))
+                         })
+                         .and_then(|info| {
+                             serde_json::to_vec(&info)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .status(hyper::StatusCode::CREATED)
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else if let Some((segment, path)) = crate::consume_path_segment(path) {
+        let claim_id = match segment.parse::<i32>() {
+            Ok(claim_id) => claim_id,
+            Err(_err) => {
+                return Box::new(futures::future::err(crate::Error::Custom(
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::BAD_REQUEST)
+                        .body("Invalid claim ID".into()),
+                )));
+            }
+        };
+
+        if path.is_empty() {
+            match *req.method() {
+                hyper::Method::DELETE => {
+                    let db_pool = db_pool.clone();
+
+                    Box::new(ensure_me(is_me)
+                             .into_future()
+                             .and_then(move |_| {
+                                 db_pool.run(move |mut conn| {
+                                     conn.prepare("DELETE FROM redirect_claims WHERE id=$1 AND user_id=$2")
+                                         .then(|res| tack_on(res, conn))
+                                         .and_then(move |(stmt, mut conn)| {
+                                             conn.execute(&stmt, &[&claim_id, &user_id.to_raw()])
+                                                 .then(|res| tack_on(res, conn))
+                                         })
+                                 })
+                                 .map_err(ErrorWrapper::from)
+                                     .map_err(crate::Error::internal)
+                             })
+                             .and_then(|count| {
+                                 if count > 0 {
+                                     hyper::Response::builder()
+                                         .status(hyper::StatusCode::NO_CONTENT)
+                                         .body(hyper::Body::empty())
+                                         .map_err(crate::Error::internal)
+                                 } else {
+                                     Err(no_such_claim())
+                                 }
+                             }))
+                }
+                _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+            }
+        } else if path == "verification/" {
+            match *req.method() {
+                hyper::Method::POST => {
+                    let db_pool = db_pool.clone();
+                    let resolver = server_state.resolver.clone();
+                    let mailer = server_state.mailer.clone();
+                    let settings = server_state.settings.clone();
+
+                    Box::new(ensure_me(is_me)
+                             .into_future()
+                             .and_then({
+                                 let db_pool = db_pool.clone();
+                                 move |_| ensure_verified(&db_pool, &settings, user_id)
+                             })
+                             .and_then({
+                                 let db_pool = db_pool.clone();
+                                 move |_| {
+                                     db_pool.run(move |mut conn| {
+                                         conn.prepare("SELECT host, token FROM redirect_claims WHERE id=$1 AND user_id=$2 AND created > localtimestamp - $3::INTEGER * INTERVAL '1 day'")
+                                             .then(|res| tack_on(res, conn))
+                                             .and_then(move |(stmt, mut conn)| {
+                                                 conn.query(&stmt, &[&claim_id, &user_id.to_raw(), &CLAIM_MAX_AGE_DAYS])
+                                                     .into_future()
+                                                     .map(|(res, _)| res)
+                                                     .map_err(|(err, _)| err)
+                                                     .then(|res| tack_on(res, conn))
+                                             })
+                                     })
+                                     .map_err(ErrorWrapper::from)
+                                         .map_err(crate::Error::internal)
+                                         .and_then(|row| row.ok_or_else(no_such_claim))
+                                 }
+                             })
+                             .and_then(move |row| {
+                                 let host: String = row.get(0);
+                                 let token: String = row.get(1);
+
+                                 resolver.txt_lookup(format!("{}.", record_name(&host)))
+                                     .then(move |res| {
+                                         // lookup errors include the record not existing
+                                         let found = match res {
+                                             Ok(lookup) => lookup.iter().any(|txt| {
+                                                 txt.txt_data().iter().any(|data| &**data == token.as_bytes())
+                                             }),
+                                             Err(_) => false,
+                                         };
+
+                                         if found {
+                                             Ok(host)
+                                         } else {
+                                             Err(crate::Error::Custom(hyper::Response::builder()
+                                                                      .status(hyper::StatusCode::BAD_REQUEST)
+                                                                      .body("The claim record was not found in DNS".into())))
+                                         }
+                                     })
+                             })
+                             .and_then(move |host| {
+                                 // takes over the existing redirect if there is one, otherwise creates it. If the claimant owns it by now, it's left alone. Nothing of the previous owner's setup carries over: rules, schedule and history are dropped, and everything else is reset as for a new redirect
+                                 db_pool.run(move |mut conn| {
+                                     conn.prepare("WITH claim AS (DELETE FROM redirect_claims WHERE id=$1 RETURNING user_id, host, destination), previous AS (SELECT users.email FROM redirects, users, claim WHERE redirects.host=claim.host AND users.id=redirects.owner AND redirects.owner<>claim.user_id), rules AS (DELETE FROM redirect_rules USING redirects, claim WHERE redirect_rules.redirect_id=redirects.id AND redirects.host=claim.host AND redirects.owner<>claim.user_id), schedule AS (DELETE FROM scheduled_changes USING redirects, claim WHERE scheduled_changes.redirect_id=redirects.id AND redirects.host=claim.host AND redirects.owner<>claim.user_id), history AS (DELETE FROM redirect_history USING redirects, claim WHERE redirect_history.redirect_id=redirects.id AND redirects.host=claim.host AND redirects.owner<>claim.user_id), updated AS (UPDATE redirects SET owner=claim.user_id, destination=claim.destination, status_code=DEFAULT, path_mode=DEFAULT, enabled=DEFAULT, holding_status_code=DEFAULT, holding_message=DEFAULT, cache_visit_count_total=DEFAULT, cache_visit_count_month=DEFAULT, tls_cert=NULL, tls_privkey=NULL, acme_failed=FALSE, record_confirmed=FALSE FROM claim WHERE redirects.host=claim.host AND redirects.owner<>claim.user_id RETURNING redirects.id), owned AS (SELECT redirects.id FROM redirects, claim WHERE redirects.host=claim.host AND redirects.owner=claim.user_id), inserted AS (INSERT INTO redirects (host, destination, owner) SELECT host, destination, user_id FROM claim WHERE NOT EXISTS (SELECT 1 FROM redirects WHERE redirects.host=claim.host) RETURNING id) SELECT (SELECT id FROM updated UNION ALL SELECT id FROM owned UNION ALL SELECT id FROM inserted), (SELECT email FROM previous)")
+                                         .then(|res| tack_on(res, conn))
+                                         .and_then(move |(stmt, mut conn)| {
+                                             conn.query(&stmt, &[&claim_id])
+                                                 .into_future()
+                                                 .map(|(res, _)| res)
+                                                 .map_err(|(err, _)| err)
+                                                 .then(|res| tack_on(res, conn))
+                                         })
+                                 })
+                                 .map_err({
+                                     let host = host.clone();
+                                     move |err| {
+                                         if crate::is_unique_violation(&err) {
+                                             super::super::redirects::host_conflict(&host)
+                                         } else {
+                                             crate::Error::internal(ErrorWrapper::from(err))
+                                         }
+                                     }
+                                 })
+                                     .and_then(|row| {
+                                         row.ok_or_else(|| crate::Error::internal(ErrorWrapper::Text("Missing result row somehow".to_owned())))
+                                     })
+                                     .and_then(move |row| {
+                                         // the claim may have been verified concurrently
+                                         let redirect_id: Option<i32> = row.get(0);
+                                         let previous_email: Option<String> = row.get(1);
+
+                                         redirect_id.ok_or_else(no_such_claim)
+                                             .map(|redirect_id| (redirect_id, previous_email, host))
+                                     })
+                             })
+                             .and_then(move |(redirect_id, previous_email, host)| {
+                                 match previous_email {
+                                     Some(previous_email) => {
+                                         futures::future::Either::A(mailer.send(crate::mail::Message {
+                                             to: previous_email,
+                                             subject: "Your redirect has been claimed".to_owned(),
+                                             body: format!("Another account proved control of the DNS for {} and has taken over its redirect.", host),
+                                         })
+                                         .map(move |_| redirect_id))
+                                     }
+                                     None => futures::future::Either::B(futures::future::ok(redirect_id)),
+                                 }
+                             })
+                             .and_then(|redirect_id| {
+                                 serde_json::to_vec(&serde_json::json!({
+                                     "redirect": redirect_id,
+                                 }))
+                                 .map_err(crate::Error::internal)
+                             })
+                             .and_then(|body| {
+                                 hyper::Response::builder()
+                                     .header(hyper::header::CONTENT_TYPE, "application/json")
+                                     .body(body.into())
+                                     .map_err(crate::Error::internal)
+                             }))
+                }
+                _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+            }
+        } else {
+            Box::new(futures::future::err(crate::Error::NotFound))
+        }
+    } else {
+        Box::new(futures::future::err(crate::Error::NotFound))
+    }
+}
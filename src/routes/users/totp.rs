@@ -0,0 +1,284 @@
+use futures::{Future, IntoFuture, Stream};
+use serde_derive::Deserialize;
+use std::sync::Arc;
+
+use super::ensure_me;
+use crate::{tack_on, DbPool, ErrorWrapper, UserID};
+
+const ISSUER: &str = "redirect.dog";
+
+#[derive(Deserialize)]
+struct TOTPCodeBody {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct TOTPDisableBody {
+    password: String,
+    code: String,
+}
+
+fn incorrect_code() -> crate::Error {
+    crate::Error::Custom(
+        hyper::Response::builder()
+            .status(hyper::StatusCode::UNAUTHORIZED)
+            .body("Incorrect two-factor code".into()),
+    )
+}
+
+fn already_enabled() -> crate::Error {
+    crate::Error::Custom(
+        hyper::Response::builder()
+            .status(hyper::StatusCode::CONFLICT)
+            .body("Two-factor authentication is already enabled".into()),
+    )
+}
+
+/// Fails with the same error as a wrong code if the code's time step was already used.
+fn record_step(
+    db_pool: &DbPool,
+    user_id: UserID,
+    step: i64,
+) -> impl Future<Item = (), Error = crate::Error> + Send {
+    crate::totp::record_step(db_pool, user_id.to_raw(), step).and_then(|fresh| {
+        if fresh {
+            Ok(())
+        } else {
+            Err(incorrect_code())
+        }
+    })
+}
+
+pub fn totp_path(
+    cpupool: &Arc<futures_cpupool::CpuPool>,
+    db_pool: &DbPool,
+    req: hyper::Request<hyper::Body>,
+    user_id: UserID,
+    is_me: bool,
+    path: &str,
+) -> Box<dyn Future<Item = hyper::Response<hyper::Body>, Error = crate::Error> + Send> {
+    if path.is_empty() {
+        match *req.method() {
+            hyper::Method::POST => {
+                // starts enrollment; it isn't enforced until confirmed with a code
+                let db_pool = db_pool.clone();
+                let secret = crate::totp::generate_secret();
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then(move |_| {
+                             let stored_secret = secret.clone();
+
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("UPDATE users SET totp_secret=$1, totp_last_step=NULL WHERE id=$2 AND NOT totp_enabled RETURNING email")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.query(&stmt, &[&stored_secret, &user_id.to_raw()])
+                                             .into_future()
+                                             .map(|(res, _)| res)
+                                             .map_err(|(err, _)| err)
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .map(move |row| (row, secret))
+                         })
+                         .and_then(|(row, secret)| {
+                             let row = row.ok_or_else(already_enabled)?;
+                             let email: String = row.get(0);
+
+                             serde_json::to_vec(&serde_json::json!({
+                                 "secret": crate::totp::base32_encode(&secret),
+                                 "uri": crate::totp::otpauth_uri(&secret, &email, ISSUER),
+                             }))
+                             .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            hyper::Method::DELETE => {
+                let cpupool = cpupool.clone();
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then(move |_| {
+                             req.into_body()
+                                 .concat2()
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             serde_json::from_slice(&body)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then({
+                             let db_pool = db_pool.clone();
+                             move |body: TOTPDisableBody| {
+                                 db_pool.run(move |mut conn| {
+                                     conn.prepare("SELECT totp_secret, passhash FROM users WHERE id=$1 AND totp_enabled")
+                                         .then(|res| tack_on(res, conn))
+                                         .and_then(move |(stmt, mut conn)| {
+                                             conn.query(&stmt, &[&user_id.to_raw()])
+                                                 .into_future()
+                                                 .map(|(res, _)| res)
+                                                 .map_err(|(err, _)| err)
+                                                 .then(|res| tack_on(res, conn))
+                                         })
+                                 })
+                                 .map_err(ErrorWrapper::from)
+                                     .map_err(crate::Error::internal)
+                                     .and_then(|row| {
+                                         row.ok_or_else(|| crate::Error::Custom(hyper::Response::builder()
+                                                                                .status(hyper::StatusCode::BAD_REQUEST)
+                                                                                .body("Two-factor authentication is not enabled".into())))
+                                     })
+                                     .and_then(move |row| {
+                                         let secret: Vec<u8> = row.get(0);
+                                         let passhash: String = row.get(1);
+                                         let TOTPDisableBody { password, code } = body;
+
+                                         cpupool.spawn_fn(move || {
+                                             crate::passwords::verify(&password, &passhash)
+                                         })
+                                             .and_then(move |correct| {
+                                                 if correct {
+                                                     Ok(())
+                                                 } else {
+                                                     Err(crate::Error::Custom(hyper::Response::builder()
+                                                                              .status(hyper::StatusCode::UNAUTHORIZED)
+                                                                              .body("Incorrect password".into())))
+                                                 }
+                                             })
+                                             .and_then(move |_| {
+                                                 crate::totp::verify(&secret, &code)
+                                                     .ok_or_else(incorrect_code)
+                                             })
+                                     })
+                             }
+                         })
+                         .and_then({
+                             let db_pool = db_pool.clone();
+                             move |step| record_step(&db_pool, user_id, step)
+                         })
+                         .and_then(move |_| {
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("WITH disabled AS (UPDATE users SET totp_enabled=FALSE, totp_secret=NULL, totp_last_step=NULL WHERE id=$1) DELETE FROM totp_recovery_codes WHERE user_id=$1")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.execute(&stmt, &[&user_id.to_raw()])
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|_| {
+                             hyper::Response::builder()
+                                 .status(hyper::StatusCode::NO_CONTENT)
+                                 .body(hyper::Body::empty())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else if let Some(path) = crate::consume_path(path, "confirmation/") {
+        if !path.is_empty() {
+            return Box::new(futures::future::err(crate::Error::NotFound));
+        }
+
+        match *req.method() {
+            hyper::Method::POST => {
+                let db_pool = db_pool.clone();
+
+                Box::new(ensure_me(is_me)
+                         .into_future()
+                         .and_then(move |_| {
+                             req.into_body()
+                                 .concat2()
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             serde_json::from_slice(&body)
+                                 .map_err(crate::Error::internal)
+                         })
+                         .and_then({
+                             let db_pool = db_pool.clone();
+                             move |body: TOTPCodeBody| {
+                                 db_pool.run(move |mut conn| {
+                                     conn.prepare("SELECT totp_secret, totp_enabled FROM users WHERE id=$1")
+                                         .then(|res| tack_on(res, conn))
+                                         .and_then(move |(stmt, mut conn)| {
+                                             conn.query(&stmt, &[&user_id.to_raw()])
+                                                 .into_future()
+                                                 .map(|(res, _)| res)
+                                                 .map_err(|(err, _)| err)
+                                                 .then(|res| tack_on(res, conn))
+                                         })
+                                 })
+                                 .map_err(ErrorWrapper::from)
+                                     .map_err(crate::Error::internal)
+                                     .and_then(move |row| {
+                                         let row = row.ok_or_else(|| crate::Error::internal(ErrorWrapper::Text("Missing user somehow".to_owned())))?;
+                                         let secret: Option<Vec<u8>> = row.get(0);
+                                         let enabled: bool = row.get(1);
+
+                                         if enabled {
+                                             return Err(already_enabled());
+                                         }
+
+                                         match secret {
+                                             Some(secret) => {
+                                                 crate::totp::verify(&secret, &body.code)
+                                                     .ok_or_else(incorrect_code)
+                                             }
+                                             None => Err(crate::Error::Custom(hyper::Response::builder()
+                                                                              .status(hyper::StatusCode::BAD_REQUEST)
+                                                                              .body("Two-factor enrollment has not been started".into()))),
+                                         }
+                                     })
+                             }
+                         })
+                         .and_then({
+                             let db_pool = db_pool.clone();
+                             move |step| record_step(&db_pool, user_id, step)
+                         })
+                         .and_then(move |_| {
+                             let codes = crate::totp::generate_recovery_codes();
+                             let hashes: Vec<String> = codes.iter().map(|code| crate::totp::hash_recovery_code(code)).collect();
+
+                             db_pool.run(move |mut conn| {
+                                 conn.prepare("WITH enabled AS (UPDATE users SET totp_enabled=TRUE WHERE id=$1), cleared AS (DELETE FROM totp_recovery_codes WHERE user_id=$1) INSERT INTO totp_recovery_codes (user_id, code_hash) SELECT $1, UNNEST($2::TEXT[])")
+                                     .then(|res| tack_on(res, conn))
+                                     .and_then(move |(stmt, mut conn)| {
+                                         conn.execute(&stmt, &[&user_id.to_raw(), &hashes])
+                                             .then(|res| tack_on(res, conn))
+                                     })
+                             })
+                             .map_err(ErrorWrapper::from)
+                                 .map_err(crate::Error::internal)
+                                 .map(|_| codes)
+                         })
+                         .and_then(|codes| {
+                             serde_json::to_vec(&serde_json::json!({
+                                 "recovery_codes": codes,
+                             }))
+                             .map_err(crate::Error::internal)
+                         })
+                         .and_then(|body| {
+                             hyper::Response::builder()
+                                 .header(hyper::header::CONTENT_TYPE, "application/json")
+                                 .body(body.into())
+                                 .map_err(crate::Error::internal)
+                         }))
+            }
+            _ => Box::new(futures::future::err(crate::Error::InvalidMethod)),
+        }
+    } else {
+        Box::new(futures::future::err(crate::Error::NotFound))
+    }
+}
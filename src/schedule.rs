@@ -0,0 +1,42 @@
+use futures::{Future, Stream};
+use std::time::Duration;
+
+use crate::{tack_on, DbPool};
+
+/// How often to check for scheduled changes that have come due.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Applies all due changes in one statement, so each is applied exactly once even with several
+/// servers polling. When more than one change for a redirect is due, the latest one wins, and every
+/// change is recorded in `redirect_history` in order.
+fn apply_due_changes(db_pool: &DbPool) -> impl Future<Item = u64, Error = ()> + Send {
+    db_pool
+        .run(|mut conn| {
+            conn.prepare("WITH due AS (DELETE FROM scheduled_changes WHERE at <= current_timestamp RETURNING id, redirect_id, at, destination), latest AS (SELECT DISTINCT ON (redirect_id) redirect_id, destination FROM due ORDER BY redirect_id, at DESC, id DESC), updated AS (UPDATE redirects SET destination=latest.destination FROM latest WHERE redirects.id=latest.redirect_id) INSERT INTO redirect_history (redirect_id, previous_destination, destination, scheduled_at, applied_at) SELECT due.redirect_id, COALESCE(LAG(due.destination) OVER (PARTITION BY due.redirect_id ORDER BY due.at, due.id), redirects.destination), due.destination, due.at, current_timestamp FROM due, redirects WHERE redirects.id=due.redirect_id")
+                .then(|res| tack_on(res, conn))
+                .and_then(|(stmt, mut conn)| {
+                    conn.execute(&stmt, &[]).then(|res| tack_on(res, conn))
+                })
+        })
+        .map_err(|err| eprintln!("Failed to apply scheduled changes: {:?}", err))
+}
+
+/// Runs for the lifetime of the server, applying scheduled destination changes as they come due.
+pub fn run(db_pool: DbPool) -> impl Future<Item = (), Error = ()> + Send {
+    tokio::timer::Interval::new_interval(POLL_INTERVAL)
+        .then(|res| -> Result<bool, ()> {
+            match res {
+                Ok(_) => Ok(true),
+                Err(err) => {
+                    eprintln!("Scheduled change timer failed: {:?}", err);
+                    // the timer only shuts down along with the runtime, anything else may recover
+                    Ok(!err.is_shutdown())
+                }
+            }
+        })
+        .take_while(|running| Ok(*running))
+        .for_each(move |_| {
+            // a failed attempt is retried on the next tick
+            apply_due_changes(&db_pool).then(|_| Ok(()))
+        })
+}
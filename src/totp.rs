@@ -0,0 +1,187 @@
+use futures::Future;
+use hmac::Mac;
+use sha2::Digest;
+
+use crate::{tack_on, DbPool, ErrorWrapper};
+
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Unpadded RFC 4648 base32, as expected in otpauth URIs.
+pub fn base32_encode(src: &[u8]) -> String {
+    let mut result = String::with_capacity((src.len() * 8 + 4) / 5);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in src {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    result
+}
+
+pub fn generate_secret() -> Vec<u8> {
+    rand::random::<[u8; 20]>().to_vec()
+}
+
+pub fn otpauth_uri(secret: &[u8], account: &str, issuer: &str) -> String {
+    let issuer = percent_encoding::utf8_percent_encode(issuer, percent_encoding::NON_ALPHANUMERIC);
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&digits={}&period={}",
+        issuer,
+        percent_encoding::utf8_percent_encode(account, percent_encoding::NON_ALPHANUMERIC),
+        base32_encode(secret),
+        issuer,
+        DIGITS,
+        STEP_SECONDS,
+    )
+}
+
+/// RFC 4226 HOTP value for a counter.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac =
+        hmac::Hmac::<sha1::Sha1>::new_varkey(secret).expect("HMAC accepts keys of any length");
+    mac.input(&counter.to_be_bytes());
+    let hash = mac.result().code();
+
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let value = (u32::from(hash[offset]) & 0x7f) << 24
+        | u32::from(hash[offset + 1]) << 16
+        | u32::from(hash[offset + 2]) << 8
+        | u32::from(hash[offset + 3]);
+
+    value % 10u32.pow(DIGITS)
+}
+
+/// Checks a code against the current time step, allowing one step of clock drift either way, and
+/// returns the step it matched. That step must then be passed to `record_step`, so the same code
+/// can't be used twice.
+pub fn verify(secret: &[u8], code: &str) -> Option<i64> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    verify_at(secret, code, now)
+}
+
+fn verify_at(secret: &[u8], code: &str, now: u64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = match code.parse() {
+        Ok(code) => code,
+        Err(_) => return None,
+    };
+
+    let counter = now / STEP_SECONDS;
+
+    [counter.saturating_sub(1), counter, counter + 1]
+        .iter()
+        .find(|counter| hotp(secret, **counter) == code)
+        .map(|counter| *counter as i64)
+}
+
+/// Stores `step` as the last one accepted for the user. Resolves to `false` if a code for this or a
+/// later step was already accepted, in which case the code must be rejected.
+pub fn record_step(
+    db_pool: &DbPool,
+    user_id: i32,
+    step: i64,
+) -> impl Future<Item = bool, Error = crate::Error> + Send {
+    db_pool
+        .run(move |mut conn| {
+            conn.prepare("UPDATE users SET totp_last_step=$2 WHERE id=$1 AND (totp_last_step IS NULL OR totp_last_step < $2)")
+                .then(|res| tack_on(res, conn))
+                .and_then(move |(stmt, mut conn)| {
+                    conn.execute(&stmt, &[&user_id, &step])
+                        .then(|res| tack_on(res, conn))
+                })
+        })
+        .map_err(ErrorWrapper::from)
+        .map_err(crate::Error::internal)
+        .map(|count| count > 0)
+}
+
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| base32_encode(&rand::random::<[u8; 5]>()))
+        .collect()
+}
+
+/// Recovery codes are random enough that a plain hash is sufficient for storage.
+pub fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|chr| chr.is_ascii_alphanumeric())
+        .map(|chr| chr.to_ascii_uppercase())
+        .collect();
+
+    sha2::Sha256::digest(code.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    /// RFC 6238 Appendix B, SHA-1. The reference values have 8 digits, of which we use the last 6.
+    const RFC_VECTORS: &[(u64, u32)] = &[
+        (59, 94287082),
+        (1111111109, 7081804),
+        (1111111111, 14050471),
+        (1234567890, 89005924),
+        (2000000000, 69279037),
+        (20000000000, 65353130),
+    ];
+
+    #[test]
+    fn rfc6238_vectors() {
+        for (time, expected) in RFC_VECTORS {
+            assert_eq!(hotp(RFC_SECRET, time / STEP_SECONDS), expected % 1_000_000);
+        }
+    }
+
+    #[test]
+    fn verify_accepts_adjacent_steps() {
+        for (time, expected) in RFC_VECTORS {
+            let code = format!("{:06}", expected % 1_000_000);
+            let step = (time / STEP_SECONDS) as i64;
+
+            assert_eq!(verify_at(RFC_SECRET, &code, *time), Some(step));
+            assert_eq!(
+                verify_at(RFC_SECRET, &code, time + STEP_SECONDS),
+                Some(step)
+            );
+            assert_eq!(
+                verify_at(RFC_SECRET, &code, time - STEP_SECONDS),
+                Some(step)
+            );
+            assert_eq!(verify_at(RFC_SECRET, &code, time + 2 * STEP_SECONDS), None);
+        }
+    }
+
+    #[test]
+    fn verify_rejects_malformed_codes() {
+        assert_eq!(verify_at(RFC_SECRET, "28708", 59), None);
+        assert_eq!(verify_at(RFC_SECRET, "94287082", 59), None);
+        assert_eq!(verify_at(RFC_SECRET, "28708a", 59), None);
+        assert_eq!(verify_at(RFC_SECRET, " 287082 ", 59), Some(1));
+    }
+}
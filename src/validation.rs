@@ -0,0 +1,901 @@
+use serde_derive::Serialize;
+
+use crate::path_mode::{fill_template, PathMode, LABEL_PLACEHOLDER};
+use crate::Settings;
+
+const MAX_HOST_LENGTH: usize = 253;
+const MAX_LABEL_LENGTH: usize = 63;
+
+const WILDCARD_PREFIX: &str = "*.";
+
+/// Stand-ins for the template placeholders while parsing a destination, so that where they ended up
+/// can be checked afterwards. `{path}` always expands to something starting with `/`.
+const PATH_STAND_IN: &str = "/rdpathplaceholder";
+const QUERY_STAND_IN: &str = "rdqueryplaceholder";
+
+/// Rejected user input, reported as a 400 with a JSON body naming the rule that failed.
+#[derive(Debug, Serialize)]
+pub struct ValidationError {
+    pub field: &'static str,
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl ValidationError {
+    fn new(field: &'static str, rule: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            field,
+            rule,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ValidationError> for crate::Error {
+    fn from(err: ValidationError) -> crate::Error {
+        let body = serde_json::to_vec(&err).expect("Failed to serialize validation error");
+
+        crate::Error::Custom(
+            hyper::Response::builder()
+                .status(hyper::StatusCode::BAD_REQUEST)
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(body.into()),
+        )
+    }
+}
+
+/// Extracts the bare host from a setting that may be a URL, like `FRONTEND_HOST`.
+fn setting_host(value: &str) -> String {
+    let value = match value.find("://") {
+        Some(idx) => &value[(idx + 3)..],
+        None => value,
+    };
+    let end = value
+        .find(|chr| chr == '/' || chr == ':')
+        .unwrap_or_else(|| value.len());
+
+    value[..end].trim_end_matches('.').to_lowercase()
+}
+
+/// Checks that `host` is a plain domain name we could serve, and returns it in canonical form:
+/// lowercase ASCII, with internationalized labels converted to punycode.
+pub fn normalize_host(host: &str, settings: &Settings) -> Result<String, ValidationError> {
+    let err = |rule, message: &str| ValidationError::new("host", rule, message);
+
+    let host = host.trim();
+    if host.is_empty() {
+        return Err(err("required", "Host must not be empty"));
+    }
+    if host.contains("://") {
+        return Err(err(
+            "no_scheme",
+            "Host must not include a scheme like https://",
+        ));
+    }
+    if host.contains(|chr| chr == '/' || chr == '?' || chr == '#') {
+        return Err(err("no_path", "Host must not include a path"));
+    }
+    if host.starts_with('[') || host.parse::<std::net::IpAddr>().is_ok() {
+        return Err(err(
+            "not_ip",
+            "Host must be a domain name, not an IP address",
+        ));
+    }
+    if host.contains(':') {
+        return Err(err("no_port", "Host must not include a port"));
+    }
+
+    let host = host.trim_end_matches('.');
+    let host = idna::domain_to_ascii(host)
+        .map_err(|_| err("valid_domain", "Host is not a valid domain name"))?;
+
+    // domain_to_ascii also normalizes alternative forms of IPv4 addresses
+    if host.parse::<std::net::IpAddr>().is_ok() {
+        return Err(err(
+            "not_ip",
+            "Host must be a domain name, not an IP address",
+        ));
+    }
+    if host.len() > MAX_HOST_LENGTH {
+        return Err(err("valid_domain", "Host is too long"));
+    }
+
+    let labels: Vec<&str> = host.split('.').collect();
+    for label in &labels {
+        if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+            return Err(err(
+                "valid_domain",
+                "Each part of the host must be between 1 and 63 characters long",
+            ));
+        }
+        if !label
+            .chars()
+            .all(|chr| chr.is_ascii_alphanumeric() || chr == '-')
+            || label.starts_with('-')
+            || label.ends_with('-')
+        {
+            return Err(err("valid_domain", "Host may only contain letters, digits and hyphens, and parts may not start or end with a hyphen"));
+        }
+    }
+    if labels.len() < 2 {
+        return Err(err(
+            "fully_qualified",
+            "Host must be a fully qualified domain name",
+        ));
+    }
+
+    let own_hosts = settings
+        .redirect_host
+        .iter()
+        .chain(settings.frontend_host.iter())
+        .map(|value| setting_host(value));
+    for own_host in own_hosts {
+        if host == own_host || host.ends_with(&format!(".{}", own_host)) {
+            return Err(err("not_reserved", "That host belongs to this service"));
+        }
+    }
+
+    Ok(host)
+}
+
+/// Like `normalize_host`, but also accepts a wildcard host like `*.example.com`, matching any single
+/// label in place of the `*`. A request is served by a wildcard only when no redirect exists for
+/// its exact host.
+///
+/// The base of a wildcard may not be a public suffix like `co.uk` or `github.io`, since its
+/// subdomains belong to unrelated owners.
+pub fn normalize_redirect_host(host: &str, settings: &Settings) -> Result<String, ValidationError> {
+    let host = host.trim();
+    if host.starts_with(WILDCARD_PREFIX) {
+        let base = normalize_host(&host[WILDCARD_PREFIX.len()..], settings)?;
+        if psl::suffix_str(&base).map_or(true, |suffix| suffix == base) {
+            return Err(ValidationError::new(
+                "host",
+                "not_public_suffix",
+                "Wildcard hosts must be under a registered domain, not a public suffix",
+            ));
+        }
+        Ok(format!("{}{}", WILDCARD_PREFIX, base))
+    } else {
+        normalize_host(host, settings)
+    }
+}
+
+/// The domain under which a wildcard host matches, or `None` for an exact host.
+pub fn wildcard_base(host: &str) -> Option<&str> {
+    if host.starts_with(WILDCARD_PREFIX) {
+        Some(&host[WILDCARD_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+/// The hosts a redirect serving requests for `host` could be stored under, in order of
+/// precedence: the exact host, then the wildcard covering it along with the label it matched.
+pub fn lookup_hosts(host: &str) -> Vec<(String, Option<&str>)> {
+    let mut hosts = vec![(host.to_owned(), None)];
+
+    if let Some(idx) = host.find('.') {
+        let (label, base) = (&host[..idx], &host[(idx + 1)..]);
+        if !label.is_empty() && base.contains('.') {
+            hosts.push((format!("{}{}", WILDCARD_PREFIX, base), Some(label)));
+        }
+    }
+
+    hosts
+}
+
+/// Hosts that destinations may not point to, beyond the built-in checks. There is no list of
+/// schemes, since destinations are always limited to http and https.
+pub struct DestinationDenylist {
+    /// Also matches subdomains.
+    pub hosts: Vec<String>,
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().trim_end_matches('.').to_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+impl DestinationDenylist {
+    /// Reads comma-separated `DENIED_DESTINATION_HOSTS`, which defaults to `localhost`.
+    pub fn from_env() -> Self {
+        Self {
+            hosts: std::env::var("DENIED_DESTINATION_HOSTS")
+                .map(|value| parse_list(&value))
+                .unwrap_or_else(|_| vec!["localhost".to_owned()]),
+        }
+    }
+}
+
+fn is_private_ipv4(addr: std::net::Ipv4Addr) -> bool {
+    let octets = addr.octets();
+
+    addr.is_private()
+        || addr.is_loopback()
+        || addr.is_link_local()
+        || addr.is_unspecified()
+        || addr.is_broadcast()
+        || addr.is_documentation()
+        // shared address space, RFC 6598
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+}
+
+fn is_private_ipv6(addr: std::net::Ipv6Addr) -> bool {
+    let first = addr.segments()[0];
+
+    addr.is_loopback()
+        || addr.is_unspecified()
+        // unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link local, fe80::/10
+        || (first & 0xffc0) == 0xfe80
+        || addr.to_ipv4().map(is_private_ipv4).unwrap_or(false)
+}
+
+/// Checks that `destination` is an absolute http(s) URL that is allowed to be redirected to from
+/// `host`, and returns it with surrounding whitespace removed.
+pub fn normalize_destination(
+    destination: &str,
+    host: &str,
+    path_mode: PathMode,
+    settings: &Settings,
+) -> Result<String, ValidationError> {
+    let err = |rule, message: &str| ValidationError::new("destination", rule, message);
+
+    let wildcard_base = wildcard_base(host);
+
+    let normalized = destination.trim();
+    let mut destination = std::borrow::Cow::Borrowed(normalized);
+    if wildcard_base.is_some() {
+        destination = std::borrow::Cow::Owned(destination.replace(LABEL_PLACEHOLDER, "label"));
+    } else if destination.contains(LABEL_PLACEHOLDER) {
+        return Err(err(
+            "valid_template",
+            "Only destinations for wildcard hosts may use {label}",
+        ));
+    }
+    if path_mode == PathMode::Template {
+        let filled = fill_template(&destination, PATH_STAND_IN, Some(QUERY_STAND_IN));
+
+        if filled == destination {
+            return Err(err(
+                "valid_template",
+                "Template destinations must use {path} or {query}",
+            ));
+        }
+
+        destination = std::borrow::Cow::Owned(filled);
+    }
+    if (path_mode == PathMode::Template || wildcard_base.is_some())
+        && destination.contains(|chr| chr == '{' || chr == '}')
+    {
+        return Err(err(
+            "valid_template",
+            "Destination may only use the {path} and {query} placeholders in template mode, and {label} for wildcard hosts",
+        ));
+    }
+
+    let url = url::Url::parse(&destination).map_err(|_| {
+        err(
+            "absolute_url",
+            "Destination must be an absolute URL, like https://example.com/",
+        )
+    })?;
+
+    if path_mode == PathMode::Template {
+        // anywhere else, the request could change where the destination points
+        let outside = [
+            url.scheme(),
+            url.username(),
+            url.password().unwrap_or(""),
+            url.host_str().unwrap_or(""),
+            url.fragment().unwrap_or(""),
+        ];
+        if outside
+            .iter()
+            .any(|part| part.contains(&PATH_STAND_IN[1..]) || part.contains(QUERY_STAND_IN))
+        {
+            return Err(err(
+                "valid_template",
+                "Placeholders may only be used in the path or query of the destination",
+            ));
+        }
+    }
+
+    if path_mode == PathMode::Append && (url.query().is_some() || url.fragment().is_some()) {
+        return Err(err(
+            "no_query",
+            "Destinations in append mode must not include a query or fragment",
+        ));
+    }
+
+    let denylist = &settings.destination_denylist;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(err("allowed_scheme", "Destination must use http or https"));
+    }
+
+    match url.host() {
+        None => return Err(err("absolute_url", "Destination must include a host")),
+        Some(url::Host::Ipv4(addr)) if is_private_ipv4(addr) => {
+            return Err(err(
+                "public_address",
+                "Destination must not point to a private address",
+            ))
+        }
+        Some(url::Host::Ipv6(addr)) if is_private_ipv6(addr) => {
+            return Err(err(
+                "public_address",
+                "Destination must not point to a private address",
+            ))
+        }
+        Some(url::Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.');
+
+            let covered_by_wildcard = wildcard_base.map_or(false, |base| {
+                domain.ends_with(&format!(".{}", base))
+                    && !domain[..(domain.len() - base.len() - 1)].contains('.')
+            });
+            if domain == host || covered_by_wildcard {
+                return Err(err(
+                    "no_loop",
+                    "Destination must not point back at the redirect itself",
+                ));
+            }
+            if denylist
+                .hosts
+                .iter()
+                .any(|denied| domain == denied || domain.ends_with(&format!(".{}", denied)))
+            {
+                return Err(err(
+                    "allowed_host",
+                    "Redirects to that destination are not allowed",
+                ));
+            }
+        }
+        Some(_) => {}
+    }
+
+    Ok(normalized.to_owned())
+}
+
+/// Status codes that may be served while a redirect is disabled.
+pub const HOLDING_STATUS_CODES: &[i32] = &[200, 404, 410, 503];
+
+const MAX_HOLDING_MESSAGE_LENGTH: usize = 4096;
+
+/// Checks that `status_code` is one of `HOLDING_STATUS_CODES`.
+pub fn validate_holding_status_code(status_code: i32) -> Result<(), ValidationError> {
+    if HOLDING_STATUS_CODES.contains(&status_code) {
+        Ok(())
+    } else {
+        Err(ValidationError::new(
+            "holding_response",
+            "allowed_status_code",
+            "Holding status code must be one of 200, 404, 410 or 503",
+        ))
+    }
+}
+
+/// Checks that a holding message isn't too long to serve.
+pub fn validate_holding_message(message: &str) -> Result<(), ValidationError> {
+    if message.len() > MAX_HOLDING_MESSAGE_LENGTH {
+        Err(ValidationError::new(
+            "holding_response",
+            "max_length",
+            "Holding message must be at most 4096 bytes",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks that a scheduled change is set for a time that hasn't passed yet.
+pub fn validate_scheduled_time(at: chrono::DateTime<chrono::Utc>) -> Result<(), ValidationError> {
+    if at <= chrono::Utc::now() {
+        Err(ValidationError::new(
+            "at",
+            "in_future",
+            "Scheduled time must be in the future",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// How many rules a single redirect may have.
+pub const MAX_RULES_PER_REDIRECT: i64 = 100;
+
+/// Reported when a rule is added to a redirect that already has `MAX_RULES_PER_REDIRECT`.
+pub fn rule_limit_reached() -> ValidationError {
+    ValidationError::new(
+        "rules",
+        "max_count",
+        format!(
+            "A redirect may have at most {} rules",
+            MAX_RULES_PER_REDIRECT
+        ),
+    )
+}
+
+/// How many changes may be scheduled for a single redirect at once.
+pub const MAX_SCHEDULED_CHANGES_PER_REDIRECT: i64 = 100;
+
+/// Reported when a change is scheduled for a redirect that already has
+/// `MAX_SCHEDULED_CHANGES_PER_REDIRECT` pending.
+pub fn scheduled_change_limit_reached() -> ValidationError {
+    ValidationError::new(
+        "schedule",
+        "max_count",
+        format!(
+            "A redirect may have at most {} scheduled changes",
+            MAX_SCHEDULED_CHANGES_PER_REDIRECT
+        ),
+    )
+}
+
+/// Checks that `pattern` can be matched against request paths, and returns it with surrounding
+/// whitespace removed. Only glob patterns may use `*`.
+pub fn normalize_rule_pattern(pattern: &str, glob: bool) -> Result<String, ValidationError> {
+    let err = |rule, message: &str| ValidationError::new("pattern", rule, message);
+
+    let pattern = pattern.trim();
+
+    if !pattern.starts_with('/') {
+        return Err(err("absolute_path", "Pattern must start with /"));
+    }
+    if pattern.contains(|chr| chr == '?' || chr == '#') {
+        return Err(err(
+            "no_query",
+            "Pattern must not include a query or fragment",
+        ));
+    }
+    if !glob && pattern.contains('*') {
+        return Err(err("no_wildcard", "Only glob patterns may use *"));
+    }
+    if glob && pattern.contains("***") {
+        return Err(err(
+            "valid_glob",
+            "Glob patterns may use * or **, but not ***",
+        ));
+    }
+
+    Ok(pattern.to_owned())
+}
+
+/// Redirect status codes that may be configured.
+pub const STATUS_CODES: &[i32] = &[301, 302, 307, 308];
+
+pub fn validate_status_code(status_code: i32) -> Result<(), ValidationError> {
+    if STATUS_CODES.contains(&status_code) {
+        Ok(())
+    } else {
+        Err(ValidationError::new(
+            "status_code",
+            "allowed_status_code",
+            "Status code must be one of 301, 302, 307 or 308",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            free_visits: 0,
+            login_max_age: None,
+            login_idle_timeout: None,
+            require_verified_email: false,
+            hash_params: crate::passwords::HashParams::default(),
+            client_ip_source: crate::client_ip::ClientIPSource::Peer,
+            destination_denylist: DestinationDenylist {
+                hosts: vec!["localhost".to_owned()],
+            },
+            frontend_host: Some("https://dashboard.example.net/".to_owned()),
+            redirect_host: Some("go.example.org".to_owned()),
+            stripe_secret_key: None,
+            stripe_publishable_key: None,
+        }
+    }
+
+    fn rule<T: std::fmt::Debug>(result: Result<T, ValidationError>) -> &'static str {
+        result.unwrap_err().rule
+    }
+
+    #[test]
+    fn host_is_normalized() {
+        let settings = settings();
+
+        assert_eq!(
+            normalize_host(" WWW.Example.COM ", &settings).unwrap(),
+            "www.example.com"
+        );
+        assert_eq!(
+            normalize_host("example.com.", &settings).unwrap(),
+            "example.com"
+        );
+        assert_eq!(
+            normalize_host("Bücher.example", &settings).unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            normalize_host("xn--bcher-kva.example", &settings).unwrap(),
+            "xn--bcher-kva.example"
+        );
+    }
+
+    #[test]
+    fn host_must_be_a_bare_domain() {
+        let settings = settings();
+
+        assert_eq!(rule(normalize_host("", &settings)), "required");
+        assert_eq!(
+            rule(normalize_host("https://example.com", &settings)),
+            "no_scheme"
+        );
+        assert_eq!(
+            rule(normalize_host("example.com/path", &settings)),
+            "no_path"
+        );
+        assert_eq!(rule(normalize_host("example.com?q", &settings)), "no_path");
+        assert_eq!(
+            rule(normalize_host("example.com:8080", &settings)),
+            "no_port"
+        );
+        assert_eq!(
+            rule(normalize_host("localhost", &settings)),
+            "fully_qualified"
+        );
+        assert_eq!(
+            rule(normalize_host("-example.com", &settings)),
+            "valid_domain"
+        );
+        assert_eq!(
+            rule(normalize_host("exa_mple.com", &settings)),
+            "valid_domain"
+        );
+        assert_eq!(
+            rule(normalize_host("example..com", &settings)),
+            "valid_domain"
+        );
+        assert_eq!(
+            rule(normalize_host(
+                &format!("{}.com", "a".repeat(MAX_LABEL_LENGTH + 1)),
+                &settings
+            )),
+            "valid_domain"
+        );
+    }
+
+    #[test]
+    fn host_must_not_be_an_ip_address() {
+        let settings = settings();
+
+        assert_eq!(rule(normalize_host("192.0.2.1", &settings)), "not_ip");
+        assert_eq!(rule(normalize_host("::1", &settings)), "not_ip");
+        assert_eq!(rule(normalize_host("[2001:db8::1]", &settings)), "not_ip");
+        assert_eq!(
+            rule(normalize_host("[2001:db8::1]:80", &settings)),
+            "not_ip"
+        );
+    }
+
+    #[test]
+    fn host_must_not_belong_to_the_service() {
+        let settings = settings();
+
+        for host in &[
+            "go.example.org",
+            "GO.example.org.",
+            "sub.go.example.org",
+            "dashboard.example.net",
+            "api.dashboard.example.net",
+        ] {
+            assert_eq!(rule(normalize_host(host, &settings)), "not_reserved");
+            assert_eq!(
+                rule(normalize_redirect_host(host, &settings)),
+                "not_reserved"
+            );
+        }
+
+        assert_eq!(
+            normalize_host("example.org", &settings).unwrap(),
+            "example.org"
+        );
+        assert_eq!(
+            normalize_host("notgo.example.org", &settings).unwrap(),
+            "notgo.example.org"
+        );
+    }
+
+    #[test]
+    fn redirect_host_normalizes_like_host() {
+        let settings = settings();
+
+        assert_eq!(
+            normalize_redirect_host(" Bücher.Example. ", &settings).unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            rule(normalize_redirect_host("example.com:443", &settings)),
+            "no_port"
+        );
+        assert_eq!(
+            rule(normalize_redirect_host("192.0.2.1", &settings)),
+            "not_ip"
+        );
+    }
+
+    fn destination(destination: &str, host: &str) -> Result<String, ValidationError> {
+        normalize_destination(destination, host, PathMode::Discard, &settings())
+    }
+
+    #[test]
+    fn destination_is_trimmed() {
+        assert_eq!(
+            destination(" https://example.org/page \n", "example.com").unwrap(),
+            "https://example.org/page"
+        );
+    }
+
+    #[test]
+    fn destination_must_be_absolute_http() {
+        for allowed in &[
+            "http://example.org",
+            "https://example.org/page?q=1#top",
+            "HTTPS://Example.org/",
+        ] {
+            assert!(destination(allowed, "example.com").is_ok(), "{}", allowed);
+        }
+
+        for scheme in &[
+            "javascript:alert(1)",
+            "data:text/html,hi",
+            "ftp://example.org/",
+            "mailto:someone@example.org",
+            "file:///etc/passwd",
+        ] {
+            assert_eq!(
+                rule(destination(scheme, "example.com")),
+                "allowed_scheme",
+                "{}",
+                scheme
+            );
+        }
+
+        for relative in &["example.org/page", "/page", "//example.org/"] {
+            assert_eq!(
+                rule(destination(relative, "example.com")),
+                "absolute_url",
+                "{}",
+                relative
+            );
+        }
+    }
+
+    #[test]
+    fn destination_must_not_be_private() {
+        for private in &[
+            "http://10.1.2.3/",
+            "http://127.0.0.1:8080/",
+            "http://2130706433/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://172.16.0.1/",
+            "http://192.168.1.1/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fe80::1]/",
+            "http://[fd12:3456::1]/",
+            "http://[::ffff:127.0.0.1]/",
+        ] {
+            assert_eq!(
+                rule(destination(private, "example.com")),
+                "public_address",
+                "{}",
+                private
+            );
+        }
+
+        assert!(destination("http://93.184.216.34/", "example.com").is_ok());
+        assert!(destination("http://[2606:2800:220:1::1]/", "example.com").is_ok());
+    }
+
+    #[test]
+    fn destination_must_not_be_denied() {
+        assert_eq!(
+            rule(destination("http://localhost:3000/", "example.com")),
+            "allowed_host"
+        );
+        assert_eq!(
+            rule(destination("http://app.localhost/", "example.com")),
+            "allowed_host"
+        );
+    }
+
+    #[test]
+    fn destination_must_not_loop() {
+        assert_eq!(
+            rule(destination("https://example.com/elsewhere", "example.com")),
+            "no_loop"
+        );
+        assert_eq!(
+            rule(destination("http://EXAMPLE.com./", "example.com")),
+            "no_loop"
+        );
+        assert!(destination("https://www.example.com/", "example.com").is_ok());
+        assert!(destination("https://example.com/", "www.example.com").is_ok());
+    }
+
+    #[test]
+    fn template_placeholders_are_validated() {
+        let template = |destination| {
+            normalize_destination(destination, "example.com", PathMode::Template, &settings())
+        };
+
+        for valid in &[
+            "https://new.example{path}",
+            "https://new.example/{path}",
+            "https://new.example/?from={query}",
+            "https://new.example{path}?{query}",
+        ] {
+            assert_eq!(template(valid).unwrap(), *valid);
+        }
+
+        for invalid in &[
+            "https://new.example/",
+            "https://new.example/{host}",
+            "https://new.example/{path",
+            "https://{path}.new.example/",
+            "https://{query}@new.example/",
+            "https://new.example/#{path}",
+            "{path}",
+        ] {
+            assert!(template(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(rule(template("https://new.example/")), "valid_template");
+        assert_eq!(
+            rule(template("https://{path}.new.example/")),
+            "valid_template"
+        );
+        assert_eq!(
+            rule(template("https://new.example/#{query}")),
+            "valid_template"
+        );
+    }
+
+    #[test]
+    fn append_destination_has_no_query() {
+        let append = |destination| {
+            normalize_destination(destination, "example.com", PathMode::Append, &settings())
+        };
+
+        assert!(append("https://new.example/base/").is_ok());
+        assert_eq!(rule(append("https://new.example/?ref=old")), "no_query");
+        assert_eq!(rule(append("https://new.example/#top")), "no_query");
+    }
+
+    #[test]
+    fn wildcard_host_is_normalized() {
+        let settings = settings();
+
+        assert_eq!(
+            normalize_redirect_host(" *.Example.COM. ", &settings).unwrap(),
+            "*.example.com"
+        );
+        assert_eq!(
+            normalize_redirect_host("*.Bücher.de", &settings).unwrap(),
+            "*.xn--bcher-kva.de"
+        );
+        assert_eq!(wildcard_base("*.example.com"), Some("example.com"));
+        assert_eq!(wildcard_base("www.example.com"), None);
+
+        for invalid in &[
+            "*example.com",
+            "**.example.com",
+            "*.*.example.com",
+            "www.*.example.com",
+            "example.*",
+        ] {
+            assert_eq!(
+                rule(normalize_redirect_host(invalid, &settings)),
+                "valid_domain",
+                "{}",
+                invalid
+            );
+        }
+        assert_eq!(
+            rule(normalize_host("*.example.com", &settings)),
+            "valid_domain"
+        );
+        assert_eq!(
+            rule(normalize_redirect_host("*.go.example.org", &settings)),
+            "not_reserved"
+        );
+    }
+
+    #[test]
+    fn wildcard_must_not_cover_a_public_suffix() {
+        let settings = settings();
+
+        for suffix in &["*.co.uk", "*.github.io", "*.CO.UK."] {
+            assert_eq!(
+                rule(normalize_redirect_host(suffix, &settings)),
+                "not_public_suffix",
+                "{}",
+                suffix
+            );
+        }
+        assert_eq!(
+            rule(normalize_redirect_host("*.com", &settings)),
+            "fully_qualified"
+        );
+
+        assert!(normalize_redirect_host("*.example.co.uk", &settings).is_ok());
+        assert!(normalize_redirect_host("*.someone.github.io", &settings).is_ok());
+    }
+
+    #[test]
+    fn label_only_for_wildcard_hosts() {
+        assert_eq!(
+            rule(destination("https://{label}.new.example/", "example.com")),
+            "valid_template"
+        );
+        assert_eq!(
+            destination("https://{label}.new.example/", "*.example.com").unwrap(),
+            "https://{label}.new.example/"
+        );
+        assert_eq!(
+            rule(destination("https://new.example/{path}", "*.example.com")),
+            "valid_template"
+        );
+        assert_eq!(
+            normalize_destination(
+                "https://new.example/{label}{path}",
+                "*.example.com",
+                PathMode::Template,
+                &settings()
+            )
+            .unwrap(),
+            "https://new.example/{label}{path}"
+        );
+    }
+
+    #[test]
+    fn wildcard_destination_must_not_loop() {
+        assert_eq!(
+            rule(destination("https://eu.example.com/", "*.example.com")),
+            "no_loop"
+        );
+        assert!(destination("https://example.com/", "*.example.com").is_ok());
+        assert!(destination("https://a.b.example.com/", "*.example.com").is_ok());
+    }
+
+    #[test]
+    fn exact_host_takes_precedence_over_wildcard() {
+        fn lookup(host: &str) -> Option<(String, Option<&str>)> {
+            let stored = ["*.example.com", "www.example.com"];
+
+            lookup_hosts(host)
+                .into_iter()
+                .find(|(candidate, _)| stored.contains(&candidate.as_str()))
+        }
+
+        assert_eq!(
+            lookup("www.example.com"),
+            Some(("www.example.com".to_owned(), None))
+        );
+        assert_eq!(
+            lookup("eu.example.com"),
+            Some(("*.example.com".to_owned(), Some("eu")))
+        );
+        assert_eq!(lookup("a.b.example.com"), None);
+        assert_eq!(lookup("example.com"), None);
+    }
+}